@@ -0,0 +1,252 @@
+//! Tagging entries one after another, while they're shown by an external tool.
+//!
+//! The line starts out with the current tags of the entry for editing. The tags entered replace
+//! its tags, and `+tag` and `-tag` words add and remove tags. A line of only those edits the
+//! current tags. An empty line or one ending in `--skip` leaves the entry alone, one ending in
+//! `--back` returns to the previous entry, and Ctrl-C or Ctrl-D end the session.
+//!
+//! Tab completes tags, suggesting the ones often used with the tags already on the line first.
+
+use completion::{TagCompleter, TagCompleterRefCell, TagStats};
+use journal::Snapshot;
+use library::Library;
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+use shell::split_args;
+use std::cell::RefCell;
+use std::process::{Child, Command};
+use tagger_map::TaggerMap;
+use term_preview::{self, Protocol};
+
+/// What entering a line does
+#[derive(Debug, PartialEq)]
+enum Input {
+    /// Leave the entry alone
+    Skip,
+    /// Return to the previous entry
+    Back,
+    /// Give the entry these tags
+    Tags(Vec<String>),
+}
+
+/// What entering `line` does to an entry with `current` tags.
+///
+/// Since the line starts out with the current tags, commands are recognized at its end.
+fn parse_line(line: &str, current: &[String]) -> Result<Input, String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    match words.last() {
+        None | Some(&"--skip") => return Ok(Input::Skip),
+        Some(&"--back") => return Ok(Input::Back),
+        _ => {}
+    }
+    let is_plain = |w: &&str| !w.starts_with('+') && !w.starts_with('-');
+    // Without any plain tags, the line only edits the current ones
+    let mut tags = if words.iter().any(is_plain) {
+        Vec::new()
+    } else {
+        current.to_vec()
+    };
+    for word in words {
+        if word.starts_with("--") {
+            return Err(format!("Unknown command {}", word));
+        }
+        let (remove, tag) = if is_plain(&word) {
+            (false, word)
+        } else {
+            (word.starts_with('-'), &word[1..])
+        };
+        if tag.is_empty() {
+            return Err(format!("Expected a tag after {}", word));
+        }
+        if remove {
+            tags.retain(|t| t != tag);
+        } else if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_owned());
+        }
+    }
+    Ok(Input::Tags(tags))
+}
+
+/// Shows entries with an external tool, or in the terminal if there's no tool.
+///
+/// The tool is started for every entry, and killed before the next one. If there's a `send`
+/// command though, the tool is started once, and `send` makes it show the next entries.
+struct Preview<'a> {
+    tool: Vec<String>,
+    send: Option<&'a str>,
+    child: Option<Child>,
+    protocol: Protocol,
+}
+
+impl<'a> Preview<'a> {
+    fn new(tool: Option<&str>, send: Option<&'a str>, protocol: Protocol) -> Self {
+        Preview {
+            tool: tool.map_or(Vec::new(), split_args),
+            send,
+            child: None,
+            protocol,
+        }
+    }
+    fn show(&mut self, name: &str) {
+        if self.tool.is_empty() {
+            if let Err(e) = term_preview::show(name, self.protocol) {
+                eprintln!("Couldn't preview {}: {}", name, e);
+            }
+            return;
+        }
+        if let (Some(send), Some(pid)) = (self.send, self.running_pid()) {
+            let words = split_args(send)
+                .iter()
+                .map(|w| w.replace("{pid}", &pid.to_string()).replace("{file}", name))
+                .collect::<Vec<_>>();
+            match words.split_first() {
+                Some((program, args)) => match Command::new(program).args(args).status() {
+                    Ok(ref status) if status.success() => return,
+                    Ok(status) => eprintln!("{} failed with {}, restarting the tool", send, status),
+                    Err(e) => eprintln!("Couldn't run {}: {}, restarting the tool", send, e),
+                },
+                None => eprintln!("The send command is empty"),
+            }
+        }
+        self.close();
+        let (program, args) = self.tool.split_first().unwrap();
+        match Command::new(program).args(args).arg(name).spawn() {
+            Ok(child) => self.child = Some(child),
+            Err(e) => eprintln!("Couldn't launch {}: {}", program, e),
+        }
+    }
+    /// The process id of the tool, if it's still running. Reports if it failed.
+    fn running_pid(&mut self) -> Option<u32> {
+        let exited = match self.child {
+            Some(ref mut child) => match child.try_wait() {
+                Ok(None) => return Some(child.id()),
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        eprintln!("{} failed with {}", self.tool[0], status);
+                    }
+                    true
+                }
+                Err(e) => {
+                    eprintln!("Couldn't check on {}: {}", self.tool[0], e);
+                    true
+                }
+            },
+            None => false,
+        };
+        if exited {
+            self.child = None;
+        }
+        None
+    }
+    /// Done with the current entry.
+    fn next(&mut self) {
+        if self.send.is_none() {
+            self.close();
+        }
+    }
+    fn close(&mut self) {
+        if self.running_pid().is_some() {
+            if let Some(mut child) = self.child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+impl<'a> Drop for Preview<'a> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Ask for the tags of the entries `names`, showing each with `tool`, or in the terminal with
+/// `protocol` if there's no tool.
+///
+/// If `send` is given, the tool is kept running, and `send` is run to make it show the next
+/// entry. `{pid}` in it is replaced with the process id of the tool, and `{file}` with the entry.
+///
+/// The list is saved after every entry, so quitting midway doesn't lose anything.
+/// Returns how many entries were changed.
+pub fn run(
+    list: &mut TaggerMap,
+    library: &Library,
+    names: &[String],
+    tool: Option<&str>,
+    send: Option<&str>,
+    protocol: Protocol,
+) -> Result<usize, String> {
+    let completer = TagCompleterRefCell(RefCell::new(TagCompleter::new(TagStats::of(list))));
+    let mut editor = Editor::new();
+    editor.set_helper(Some(&completer));
+    let mut preview = Preview::new(tool, send, protocol);
+    let mut changed = 0;
+    // Indices of the entries visited before the current one, for going back
+    let mut visited = Vec::new();
+    let mut i = 0;
+    while i < names.len() {
+        let name = &names[i];
+        let current = list.tag_map.entries.get(name).cloned().unwrap_or_default();
+        // The terminal preview clears the screen, so it has to come first
+        preview.show(name);
+        if !current.is_empty() {
+            let context = current.iter().map(|t| &t[..]).collect::<Vec<_>>();
+            let suggestions = completer.0.borrow().stats.suggest("", &context);
+            if !suggestions.is_empty() {
+                println!("Often used with the current tags: {}", suggestions.join(" "));
+            }
+        }
+        let prompt = format!("Tags for {}: ", name);
+        let line = editor.readline_with_initial(&prompt, (&current.join(" "), ""));
+        preview.next();
+        let line = match line {
+            Ok(line) => line,
+            Err(ReadlineError::Eof) | Err(ReadlineError::Interrupted) => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        match parse_line(&line, &current) {
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+            Ok(Input::Skip) => {}
+            Ok(Input::Back) => {
+                match visited.pop() {
+                    Some(previous) => i = previous,
+                    None => println!("This is the first entry."),
+                }
+                continue;
+            }
+            Ok(Input::Tags(tags)) => {
+                editor.add_history_entry(line.trim());
+                if tags != current {
+                    let stats = &mut completer.0.borrow_mut().stats;
+                    stats.remove_entry(&current);
+                    stats.add_entry(&tags);
+                    let before = Snapshot::of(list);
+                    list.tag_map.entries.insert(name.clone(), tags);
+                    ::save_list(library, list, &before, &format!("add-tags {}", name), Vec::new())?;
+                    changed += 1;
+                }
+            }
+        }
+        visited.push(i);
+        i += 1;
+    }
+    Ok(changed)
+}
+
+#[test]
+fn test_parse_line() {
+    let current = vec!["cat".to_owned(), "cute".to_owned()];
+    let tags = |tags: &[&str]| Ok(Input::Tags(tags.iter().map(|&t| t.to_owned()).collect()));
+    assert_eq!(parse_line("dog cute dog", &current), tags(&["dog", "cute"]));
+    assert_eq!(parse_line("+dog -cute +cat", &current), tags(&["cat", "dog"]));
+    assert_eq!(parse_line("cat cute +dog", &current), tags(&["cat", "cute", "dog"]));
+    assert_eq!(parse_line("cat cute -cute", &current), tags(&["cat"]));
+    assert_eq!(parse_line("  ", &current), Ok(Input::Skip));
+    assert_eq!(parse_line("cat cute --skip", &current), Ok(Input::Skip));
+    assert_eq!(parse_line("cat cute --back", &current), Ok(Input::Back));
+    assert!(parse_line("cat --back cute", &current).is_err());
+    assert!(parse_line("cat cute +", &current).is_err());
+}
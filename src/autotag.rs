@@ -0,0 +1,340 @@
+//! Tags derived from the files themselves.
+//!
+//! They're in namespaces starting with `auto.`, like `auto.ext:png` and
+//! `auto.orientation:landscape`, so they can be refreshed without touching the other tags.
+
+use image::ImageDecoder;
+use image::bmp::BMPDecoder;
+use image::ico::ICODecoder;
+use image::png::PNGDecoder;
+use image::tiff::TIFFDecoder;
+use std::fs;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Prefix of the namespaces of automatic tags
+pub const PREFIX: &str = "auto.";
+
+/// How much of a file is read to tell what it is. The headers of PNG, BMP and ICO images are well
+/// within it; the other formats are read further as needed.
+const HEADER_SIZE: u64 = 64 * 1024;
+
+/// Bytes found at an offset from the start of a file
+type Magic = (usize, &'static [u8]);
+
+/// Known file signatures, by the bytes found at offsets from the start. More specific ones come
+/// first.
+const SIGNATURES: &[(&[Magic], &str)] = &[
+    (&[(0, b"\x89PNG\r\n\x1a\n")], "image/png"),
+    (&[(0, b"\xff\xd8\xff")], "image/jpeg"),
+    (&[(0, b"GIF87a")], "image/gif"),
+    (&[(0, b"GIF89a")], "image/gif"),
+    // The reserved fields after the file size are zero
+    (&[(0, b"BM"), (6, b"\0\0\0\0")], "image/bmp"),
+    (&[(0, b"II*\0")], "image/tiff"),
+    (&[(0, b"MM\0*")], "image/tiff"),
+    (&[(0, b"\0\0\x01\0")], "image/x-icon"),
+    (&[(0, b"RIFF"), (8, b"WEBP")], "image/webp"),
+    (&[(0, b"RIFF"), (8, b"AVI ")], "video/x-msvideo"),
+    (&[(0, b"RIFF"), (8, b"WAVE")], "audio/wav"),
+    // ISO media files tell what they are by the brand after `ftyp`
+    (&[(4, b"ftyp"), (8, b"avif")], "image/avif"),
+    (&[(4, b"ftyp"), (8, b"avis")], "image/avif"),
+    (&[(4, b"ftyp"), (8, b"heic")], "image/heic"),
+    (&[(4, b"ftyp"), (8, b"heix")], "image/heic"),
+    (&[(4, b"ftyp"), (8, b"mif1")], "image/heif"),
+    (&[(4, b"ftyp"), (8, b"msf1")], "image/heif"),
+    (&[(4, b"ftyp"), (8, b"qt  ")], "video/quicktime"),
+    (&[(4, b"ftyp"), (8, b"M4A ")], "audio/mp4"),
+    (&[(4, b"ftyp")], "video/mp4"),
+    (&[(0, b"\x1a\x45\xdf\xa3")], "video/webm"),
+    (&[(0, b"OggS")], "audio/ogg"),
+    (&[(0, b"ID3")], "audio/mpeg"),
+    (&[(0, b"fLaC")], "audio/flac"),
+    (&[(0, b"%PDF")], "application/pdf"),
+    (&[(0, b"PK\x03\x04")], "application/zip"),
+];
+
+/// The MIME type of a file, going by its first bytes.
+pub fn sniff_mime(data: &[u8]) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|&&(parts, _)| {
+            parts
+                .iter()
+                .all(|&(offset, magic)| data.get(offset..offset + magic.len()) == Some(magic))
+        })
+        .map(|&(_, mime)| mime)
+}
+
+pub fn u16_be(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 2).map(|b| u32::from(b[0]) << 8 | u32::from(b[1]))
+}
+
+pub fn u16_le(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 2).map(|b| u32::from(b[1]) << 8 | u32::from(b[0]))
+}
+
+pub fn u32_be(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16_be(data, at)? << 16 | u16_be(data, at + 2)?)
+}
+
+pub fn u32_le(data: &[u8], at: usize) -> Option<u32> {
+    Some(u16_le(data, at + 2)? << 16 | u16_le(data, at)?)
+}
+
+/// Fill `buf` from `reader`. Returns false if it ends first.
+pub fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The next `len` bytes of `reader`, or fewer if it ends first.
+pub fn read_up_to<R: Read>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(len).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Read the start of the next segment of a JPEG file, up to the image data.
+///
+/// Returns its marker and the length of its contents, which `file` is left at.
+pub fn next_jpeg_segment<R: Read>(file: &mut R) -> io::Result<Option<(u8, u64)>> {
+    let mut segment = [0; 4];
+    if !fill(file, &mut segment)? || segment[0] != 0xff {
+        return Ok(None);
+    }
+    let (marker, len) = (segment[1], u16_be(&segment, 2).unwrap());
+    if marker == 0xda || marker == 0xd9 || len < 2 {
+        return Ok(None);
+    }
+    Ok(Some((marker, u64::from(len - 2))))
+}
+
+/// The dimensions of a JPEG image, from the frame header. The segments before it are skipped.
+fn jpeg_dimensions<R: Read + Seek>(file: &mut R) -> io::Result<Option<(u32, u32)>> {
+    file.seek(SeekFrom::Start(2))?;
+    while let Some((marker, len)) = next_jpeg_segment(file)? {
+        match marker {
+            // These share the range of the start of frame markers
+            0xc4 | 0xc8 | 0xcc => {}
+            0xc0..=0xcf => {
+                let frame = read_up_to(file, 5)?;
+                return Ok(match (u16_be(&frame, 3), u16_be(&frame, 1)) {
+                    (Some(width), Some(height)) => Some((width, height)),
+                    _ => None,
+                });
+            }
+            _ => {}
+        }
+        file.seek(SeekFrom::Current(len as i64))?;
+    }
+    Ok(None)
+}
+
+/// Skip the next `len` bytes of `reader`.
+fn skip<R: Read>(reader: &mut R, len: u64) -> io::Result<()> {
+    io::copy(&mut reader.take(len), &mut io::sink()).map(|_| ())
+}
+
+/// Whether a GIF file has more than one image, going by its blocks without decoding them.
+fn is_animated_gif<R: Read + Seek>(file: &mut R, header: &[u8]) -> io::Result<bool> {
+    // The blocks come after the global color table
+    let flags = header.get(10).map_or(0, |&f| f);
+    let color_table = |flags: u8| if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 };
+    file.seek(SeekFrom::Start(13 + color_table(flags)))?;
+    // Blocks are mostly small, so they're read through rather than seeked over
+    let mut file = BufReader::new(file);
+    let mut images = 0;
+    let mut byte = [0];
+    while fill(&mut file, &mut byte)? {
+        match byte[0] {
+            // Image descriptor, followed by a local color table and the LZW code size
+            0x2c => {
+                images += 1;
+                if images > 1 {
+                    return Ok(true);
+                }
+                let mut descriptor = [0; 9];
+                if !fill(&mut file, &mut descriptor)? {
+                    break;
+                }
+                skip(&mut file, color_table(descriptor[8]) + 1)?;
+            }
+            // Extension, with its label
+            0x21 => skip(&mut file, 1)?,
+            // The trailer, or something that isn't a GIF block
+            _ => break,
+        }
+        // Both are followed by data sub-blocks, each starting with its length
+        while fill(&mut file, &mut byte)? && byte[0] != 0 {
+            skip(&mut file, u64::from(byte[0]))?;
+        }
+    }
+    Ok(false)
+}
+
+/// Whether a PNG file has an animation control chunk, which comes before the image data.
+fn is_animated_png(data: &[u8]) -> bool {
+    let mut at = 8;
+    while let Some(len) = u32_be(data, at) {
+        match data.get(at + 4..at + 8) {
+            Some(b"acTL") => return true,
+            Some(b"IDAT") | None => return false,
+            _ => at += 12 + len as usize,
+        }
+    }
+    false
+}
+
+/// Dimensions and whether the image is animated, of an image `file` starting with `header`.
+fn image_info<R: Read + Seek>(
+    file: &mut R,
+    header: &[u8],
+    mime: &str,
+) -> io::Result<Option<(u32, u32, bool)>> {
+    let reader = io::Cursor::new(header);
+    let (dimensions, animated) = match mime {
+        "image/png" => (PNGDecoder::new(reader).dimensions(), is_animated_png(header)),
+        "image/gif" => {
+            let (width, height) = match (u16_le(header, 6), u16_le(header, 8)) {
+                (Some(width), Some(height)) => (width, height),
+                _ => return Ok(None),
+            };
+            return Ok(Some((width, height, is_animated_gif(file, header)?)));
+        }
+        "image/jpeg" => {
+            let dimensions = jpeg_dimensions(file)?;
+            return Ok(dimensions.map(|(width, height)| (width, height, false)));
+        }
+        "image/bmp" => (BMPDecoder::new(reader).dimensions(), false),
+        // The directory of a TIFF file can be anywhere in it
+        "image/tiff" => {
+            file.seek(SeekFrom::Start(0))?;
+            (TIFFDecoder::new(file).and_then(|mut d| d.dimensions()), false)
+        }
+        "image/x-icon" => (ICODecoder::new(reader).and_then(|mut d| d.dimensions()), false),
+        // The WebP decoder of the image crate decodes the whole image to get its size, and only
+        // knows lossy images, so the chunk headers are read here
+        "image/webp" => return Ok(webp_info(header)),
+        _ => return Ok(None),
+    };
+    Ok(dimensions.ok().map(|(width, height)| (width, height, animated)))
+}
+
+/// Dimensions and whether the image is animated, read from the header of a WebP file.
+fn webp_info(header: &[u8]) -> Option<(u32, u32, bool)> {
+    match header.get(12..16)? {
+        b"VP8X" => {
+            let width = u32_le(header, 24)? & 0xff_ffff;
+            let height = u32_le(header, 27)? & 0xff_ffff;
+            Some((width + 1, height + 1, header[20] & 0x02 != 0))
+        }
+        b"VP8 " => Some((
+            u16_le(header, 26)? & 0x3fff,
+            u16_le(header, 28)? & 0x3fff,
+            false,
+        )),
+        b"VP8L" => {
+            let bits = u32_le(header, 21)?;
+            Some(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1, false))
+        }
+        _ => None,
+    }
+}
+
+fn size_bucket(len: u64) -> &'static str {
+    if len < 100_000 {
+        "small"
+    } else if len < 1_000_000 {
+        "medium"
+    } else if len < 10_000_000 {
+        "large"
+    } else {
+        "huge"
+    }
+}
+
+/// The automatic tags of the file at `path`.
+pub fn file_tags(path: &Path) -> io::Result<Vec<String>> {
+    let mut tags = Vec::new();
+    let metadata = fs::metadata(path)?;
+    if metadata.is_dir() {
+        tags.push(format!("{}type:directory", PREFIX));
+        return Ok(tags);
+    }
+    if let Some(ext) = path.extension() {
+        tags.push(format!("{}ext:{}", PREFIX, ext.to_string_lossy().to_lowercase()));
+    }
+    tags.push(format!("{}size:{}", PREFIX, size_bucket(metadata.len())));
+    let mut file = fs::File::open(path)?;
+    let header = read_up_to(&mut file, HEADER_SIZE)?;
+    let mime = match sniff_mime(&header) {
+        Some(mime) => mime,
+        None => return Ok(tags),
+    };
+    tags.push(format!("{}mime:{}", PREFIX, mime));
+    if let Some((width, height, animated)) = image_info(&mut file, &header, mime)? {
+        let orientation = if width > height {
+            "landscape"
+        } else if width < height {
+            "portrait"
+        } else {
+            "square"
+        };
+        tags.push(format!("{}width:{}", PREFIX, width));
+        tags.push(format!("{}height:{}", PREFIX, height));
+        tags.push(format!("{}orientation:{}", PREFIX, orientation));
+        let animation = if animated { "animated" } else { "static" };
+        tags.push(format!("{}animation:{}", PREFIX, animation));
+    }
+    Ok(tags)
+}
+
+/// `tags` with the ones in namespaces starting with `prefix` replaced by `found`.
+pub fn replace_tags(tags: &[String], prefix: &str, found: Vec<String>) -> Vec<String> {
+    let mut new_tags = tags.iter()
+        .filter(|t| !t.starts_with(prefix))
+        .cloned()
+        .collect::<Vec<_>>();
+    new_tags.extend(found);
+    new_tags
+}
+
+#[test]
+fn test_image_info() {
+    use image::{self, gif, RGBA};
+    let mut png = Vec::new();
+    image::png::PNGEncoder::new(&mut png)
+        .encode(&[0; 4 * 2 * 4], 4, 2, RGBA(8))
+        .unwrap();
+    assert_eq!(sniff_mime(&png), Some("image/png"));
+    let info = |data: &[u8], mime| image_info(&mut io::Cursor::new(data), data, mime).unwrap();
+    assert_eq!(info(&png, "image/png"), Some((4, 2, false)));
+    let mut gif = Vec::new();
+    let frame = gif::Frame::from_rgba(2, 3, &mut [0; 2 * 3 * 4]);
+    gif::Encoder::new(&mut gif).encode(frame).unwrap();
+    assert_eq!(info(&gif, "image/gif"), Some((2, 3, false)));
+    // Repeat the image, which comes after the global color table, before the trailer
+    let image = gif[13 + (3 << ((gif[10] & 0x07) + 1))..gif.len() - 1].to_vec();
+    let at = gif.len() - 1;
+    gif.splice(at..at, image);
+    assert_eq!(info(&gif, "image/gif"), Some((2, 3, true)));
+    // The frame header is past the start of the file that autotag reads at first
+    let mut jpeg = b"\xff\xd8".to_vec();
+    for _ in 0..2 {
+        jpeg.extend(b"\xff\xe2\xa0\0");
+        jpeg.extend(&[0; 0xa000 - 2][..]);
+    }
+    jpeg.extend(b"\xff\xc0\0\x0b\x08\x01\x2c\x01\x90\x01\x01\x11\0\xff\xda");
+    assert!(jpeg.len() as u64 > HEADER_SIZE);
+    assert_eq!(info(&jpeg, "image/jpeg"), Some((400, 300, false)));
+    assert_eq!(sniff_mime(b"BMW is a car"), None);
+    assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBPVP8X"), Some("image/webp"));
+    assert_eq!(sniff_mime(b"junkjunkWEBP"), None);
+    assert_eq!(sniff_mime(b"\0\0\0\x18ftypheic"), Some("image/heic"));
+    assert_eq!(sniff_mime(b"\0\0\0\x18ftypisom"), Some("video/mp4"));
+}
@@ -0,0 +1,175 @@
+use rustyline::{self, Context, Helper};
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use std::cell::RefCell;
+use fuzzy::{fuzzy_matches, fuzzy_score};
+use std::collections::BTreeMap;
+use tagger_map::TaggerMap;
+
+/// How many suggestions are offered for an empty word
+const SUGGESTION_COUNT: usize = 20;
+
+/// How often tags are used, and how often they're used together.
+#[derive(Default)]
+pub struct TagStats {
+    counts: BTreeMap<String, usize>,
+    /// For each tag, how often the other tags appear on the same entries
+    together: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+impl TagStats {
+    pub fn of(list: &TaggerMap) -> Self {
+        let mut stats = TagStats::default();
+        for tags in list.tag_map.entries.values() {
+            stats.add_entry(tags);
+        }
+        stats
+    }
+    /// Count the tags of an entry.
+    pub fn add_entry(&mut self, tags: &[String]) {
+        for tag in tags {
+            *self.counts.entry(tag.clone()).or_insert(0) += 1;
+            let together = self.together.entry(tag.clone()).or_default();
+            for other in tags.iter().filter(|&other| other != tag) {
+                *together.entry(other.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+    /// Stop counting the tags of an entry, when they're changed.
+    pub fn remove_entry(&mut self, tags: &[String]) {
+        for tag in tags {
+            if let Some(count) = self.counts.get_mut(tag) {
+                *count = count.saturating_sub(1);
+            }
+            if let Some(together) = self.together.get_mut(tag) {
+                for other in tags.iter().filter(|&other| other != tag) {
+                    if let Some(count) = together.get_mut(other) {
+                        *count = count.saturating_sub(1);
+                    }
+                }
+            }
+        }
+        self.counts.retain(|_, &mut count| count > 0);
+    }
+    /// Tags matching `word` fuzzily, that aren't in `context` yet.
+    ///
+    /// Tags that often appear together with the ones in `context` come first, then the ones
+    /// matching best, then the ones used most. Without a word, only the best few are suggested.
+    pub fn suggest(&self, word: &str, context: &[&str]) -> Vec<String> {
+        let mut candidates = self.counts
+            .iter()
+            .filter(|&(tag, _)| !context.contains(&&tag[..]))
+            .filter_map(|(tag, &count)| {
+                let score = fuzzy_score(word, tag)?;
+                let together: usize = context
+                    .iter()
+                    .filter_map(|&c| self.together.get(c).and_then(|t| t.get(tag)))
+                    .sum();
+                Some(((together, score, count), tag))
+            })
+            .filter(|&((together, _, _), _)| !word.is_empty() || context.is_empty() || together > 0)
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+        if word.is_empty() {
+            candidates.truncate(SUGGESTION_COUNT);
+        }
+        candidates.into_iter().map(|(_, tag)| tag.clone()).collect()
+    }
+}
+
+pub struct TagCompleter {
+    pub stats: TagStats,
+}
+
+impl TagCompleter {
+    pub fn new(stats: TagStats) -> Self {
+        TagCompleter { stats }
+    }
+}
+
+pub struct TagCompleterRefCell(pub RefCell<TagCompleter>);
+
+impl Completer for TagCompleterRefCell {
+    type Candidate = String;
+    /// Complete tags, ranked by the tags already on the line.
+    ///
+    /// `+tag` and `-tag` words are completed too.
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let begin = line[..pos].rfind(' ').map_or(0, |p| p + 1);
+        let mut word = &line[begin..pos];
+        let sign = if word.starts_with('+') || word.starts_with('-') { 1 } else { 0 };
+        word = &word[sign..];
+        let context = line[..begin]
+            .split_whitespace()
+            .filter(|w| !w.starts_with('-'))
+            .map(|w| w.strip_prefix('+').unwrap_or(w))
+            .collect::<Vec<_>>();
+        let candidates = self.0.borrow().stats.suggest(word, &context);
+        Ok((begin + sign, candidates))
+    }
+}
+
+impl Hinter for TagCompleterRefCell {
+    type Hint = String;
+}
+
+impl Highlighter for TagCompleterRefCell {}
+
+impl Validator for TagCompleterRefCell {}
+
+impl Helper for TagCompleterRefCell {}
+
+/// Returns the beginning of the word under the cursor, and the candidates that match it.
+pub fn complete_word<'a, I>(line: &str, pos: usize, candidates: I) -> (usize, Vec<String>)
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    // Beginning of word is either space before it, or 0
+    let begin = line[..pos].rfind(' ').map_or(0, |p| p + 1);
+    (begin, fuzzy_matches(&line[begin..pos], candidates))
+}
+
+/// Like `complete_word`, but words of queries can also be separated by operators.
+pub fn complete_query_word<'a, I>(line: &str, pos: usize, candidates: I) -> (usize, Vec<String>)
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let begin = line[..pos]
+        .rfind(|c: char| c.is_whitespace() || "!()|&".contains(c))
+        .map_or(0, |p| p + 1);
+    (begin, fuzzy_matches(&line[begin..pos], candidates))
+}
+
+#[test]
+fn test_suggest() {
+    let mut stats = TagStats::default();
+    let entry = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    stats.add_entry(&entry(&["cat", "cute", "animal"]));
+    stats.add_entry(&entry(&["dog", "animal"]));
+    stats.add_entry(&entry(&["dog", "animal"]));
+    stats.add_entry(&entry(&["car", "fast"]));
+    assert_eq!(stats.suggest("c", &[]), ["car", "cat", "cute"]);
+    assert_eq!(stats.suggest("c", &["animal"]), ["cat", "cute", "car"]);
+    assert_eq!(stats.suggest("", &["cat"]), ["animal", "cute"]);
+    stats.remove_entry(&entry(&["car", "fast"]));
+    assert_eq!(stats.suggest("ca", &[]), ["cat"]);
+}
+
+#[test]
+fn test_complete_query_word() {
+    let tags = ["black_cat", "cheat", "cat", "dog"]
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        complete_query_word("foo !(ca", 8, &tags),
+        (6, vec!["cat".to_owned(), "black_cat".to_owned(), "cheat".to_owned()])
+    );
+}
@@ -0,0 +1,239 @@
+//! User settings, read from the user config file and from an optional config file of the library.
+//!
+//! Config files consist of `key = value` lines. Lines starting with `#` are comments.
+
+use exif;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The settings tagger understands, along with their descriptions
+pub const KEYS: &[(&str, &str)] = &[
+    (
+        "viewer",
+        "Command template used to open entries, see `viewer.EXT` (default: xdg-open {file})",
+    ),
+    (
+        "viewer.EXT",
+        "Command template for files with the extension EXT, with {files}, {file} and {dir} \
+         placeholders",
+    ),
+    ("gui.columns", "Number of thumbnails per row in the gui (default: 5)"),
+    (
+        "gui.thumbnail-size",
+        "Size of the thumbnails in pixels (default: window width / gui.columns)",
+    ),
+    ("gui.scroll-speed", "Pixels scrolled per frame with the arrow keys (default: 8)"),
+    (
+        "gui.loader-threads",
+        "Number of threads loading thumbnails in the gui. Each holds one image in memory at a \
+         time. (default: 4)",
+    ),
+    (
+        "gui.texture-memory",
+        "MiB of thumbnails the gui keeps in memory. Thumbnails far from the screen are dropped to \
+         stay below it. (default: 512)",
+    ),
+    ("add-tags.tool", "Tool add-tags shows entries with, if --with isn't given"),
+    (
+        "add-tags.send",
+        "Command that makes the running add-tags tool show the next entry, if --send isn't given",
+    ),
+    (
+        "add-tags.preview",
+        "How add-tags shows images without a tool: auto, kitty, sixel or blocks (default: auto)",
+    ),
+    (
+        "output.format",
+        "How filt and random print entries: relative, absolute or name (default: relative)",
+    ),
+    (
+        "exif.fields",
+        "Space separated EXIF fields the exif command reads: make, model, lens, date, iso, \
+         exposure, aperture, focal and gps (default: make model lens date gps)",
+    ),
+    (
+        "ignore",
+        "Space separated file name patterns that gen and update skip. * and ? are wildcards.",
+    ),
+];
+
+/// The user config file, `$XDG_CONFIG_HOME/tagger/config`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("tagger").join("config"))
+}
+
+fn is_known(key: &str) -> bool {
+    KEYS.iter().any(|&(k, _)| k == key) || key.starts_with("viewer.")
+}
+
+/// Check that `key` is a known setting, and that `value` makes sense for it.
+pub fn validate(key: &str, value: &str) -> Result<(), String> {
+    if !is_known(key) {
+        return Err(format!("Unknown setting: {}", key));
+    }
+    let valid = match key {
+        "gui.columns" | "gui.thumbnail-size" | "gui.loader-threads" | "gui.texture-memory" => {
+            value.parse::<u32>().is_ok_and(|n| n > 0)
+        }
+        "gui.scroll-speed" => value.parse::<f32>().is_ok(),
+        "output.format" => ["relative", "absolute", "name"].contains(&value),
+        "add-tags.preview" => ["auto", "kitty", "sixel", "blocks"].contains(&value),
+        "exif.fields" => value.split_whitespace().all(exif::is_field),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Invalid value for {}: {}", key, value))
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut values = BTreeMap::new();
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(values),
+        Err(e) => return Err(e),
+    };
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => {
+                values.insert(key.trim().to_owned(), value.trim().to_owned());
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Line {} is not a `key = value` line", i + 1),
+                ))
+            }
+        }
+    }
+    Ok(values)
+}
+
+/// Set `key` to `value` in the config file at `path`, or remove it if `value` is `None`.
+///
+/// Other lines of the file are left alone.
+pub fn write_value(path: &Path, key: &str, value: Option<&str>) -> io::Result<()> {
+    let mut contents = String::new();
+    match fs::File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut lines = Vec::new();
+    let mut value = value;
+    for line in contents.lines() {
+        let is_key = line.split('=').next().map(|k| k.trim()) == Some(key)
+            && !line.trim().starts_with('#');
+        if !is_key {
+            lines.push(line.to_owned());
+        } else if let Some(value) = value.take() {
+            lines.push(format!("{} = {}", key, value));
+        }
+    }
+    if let Some(value) = value {
+        lines.push(format!("{} = {}", key, value));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::File::create(path)?;
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// The settings in effect. Settings of the library override the ones of the user.
+pub struct Config {
+    values: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn load(library_config: Option<&Path>) -> Result<Self, String> {
+        let mut values = BTreeMap::new();
+        let user_config = user_config_path();
+        let paths = user_config.as_deref().into_iter().chain(library_config);
+        for path in paths {
+            let file_values =
+                read_file(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+            for (key, value) in file_values {
+                // It may be a setting of a newer version
+                if !is_known(&key) {
+                    eprintln!("Warning: {}: Ignoring unknown setting {}", path.display(), key);
+                    continue;
+                }
+                validate(&key, &value).map_err(|e| format!("{}: {}", path.display(), e))?;
+                values.insert(key, value);
+            }
+        }
+        Ok(Config { values })
+    }
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| &v[..])
+    }
+    pub fn values(&self) -> &BTreeMap<String, String> {
+        &self.values
+    }
+    /// The value of a setting, which has already been checked by `validate`.
+    pub fn parse<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+    pub fn ignore_patterns(&self) -> Vec<&str> {
+        self.get("ignore").map_or(Vec::new(), |v| v.split_whitespace().collect())
+    }
+}
+
+/// Whether `text` matches a pattern where `*` stands for any run of characters, and `?` for a
+/// single character.
+pub fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    // Position in the pattern after the last *, and the position in the text it matched up to
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last * match one more character
+            star = Some((star_p, star_t + 1));
+            p = star_p;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_pattern_matches() {
+    assert!(pattern_matches("*.tmp", "a.tmp"));
+    assert!(pattern_matches("*.tmp", ".tmp"));
+    assert!(!pattern_matches("*.tmp", "a.tmp.png"));
+    assert!(pattern_matches("a*b?c", "axxbyc"));
+    assert!(pattern_matches("a*b*", "ab"));
+    assert!(!pattern_matches("a?", "a"));
+}
@@ -0,0 +1,338 @@
+//! Tags read from the EXIF metadata of photos.
+//!
+//! They're in namespaces starting with `exif.`, like `exif.model:canon_eos_80d`. The date a photo
+//! was taken becomes `exif.date:2017-05-03`, `exif.month:2017-05` and `exif.year:2017`, so photos
+//! can be looked up by any of them. The `exif.fields` setting selects the fields that are read.
+
+use autotag::{fill, next_jpeg_segment, read_up_to, sniff_mime, u16_be, u16_le, u32_be, u32_le};
+use infix;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Prefix of the namespaces of EXIF tags
+pub const PREFIX: &str = "exif.";
+
+/// The fields read if `exif.fields` isn't set
+pub const DEFAULT_FIELDS: &str = "make model lens date gps";
+
+/// Tag of the pointer to the EXIF IFD, which has most of the photo related fields
+const EXIF_IFD: u32 = 0x8769;
+/// Tag of the date the file was last changed, used if there's no date the photo was taken
+const DATE_TIME: u32 = 0x0132;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Ifd {
+    Main,
+    Exif,
+}
+
+/// The fields that can be read, with the IFD and the tag they're found at
+const FIELDS: &[(&str, Ifd, u32)] = &[
+    ("make", Ifd::Main, 0x010f),
+    ("model", Ifd::Main, 0x0110),
+    ("lens", Ifd::Exif, 0xa434),
+    ("date", Ifd::Exif, 0x9003),
+    ("iso", Ifd::Exif, 0x8827),
+    ("exposure", Ifd::Exif, 0x829a),
+    ("aperture", Ifd::Exif, 0x829d),
+    ("focal", Ifd::Exif, 0x920a),
+    // Pointer to the GPS IFD, only checked for presence
+    ("gps", Ifd::Main, 0x8825),
+];
+
+pub fn is_field(name: &str) -> bool {
+    FIELDS.iter().any(|&(n, _, _)| n == name)
+}
+
+/// The EXIF data in a JPEG, PNG or WebP file, which is laid out like a TIFF file.
+///
+/// Only the EXIF data is read, the rest of the file is skipped.
+fn exif_block<R: Read + Seek>(file: &mut R, mime: &str) -> io::Result<Option<Vec<u8>>> {
+    match mime {
+        "image/jpeg" => {
+            // It's in an APP1 segment before the image data
+            file.seek(SeekFrom::Start(2))?;
+            while let Some((marker, len)) = next_jpeg_segment(file)? {
+                if marker == 0xe1 {
+                    let mut data = read_up_to(file, len)?;
+                    if data.starts_with(b"Exif\0\0") {
+                        data.drain(..6);
+                        return Ok(Some(data));
+                    }
+                } else {
+                    file.seek(SeekFrom::Current(len as i64))?;
+                }
+            }
+        }
+        "image/png" => {
+            file.seek(SeekFrom::Start(8))?;
+            let mut chunk = [0; 8];
+            while fill(file, &mut chunk)? {
+                let len = u32_be(&chunk, 0).unwrap();
+                match &chunk[4..] {
+                    b"eXIf" => return read_up_to(file, u64::from(len)).map(Some),
+                    b"IEND" => break,
+                    // Skip the data and the checksum after it
+                    _ => file.seek(SeekFrom::Current(i64::from(len) + 4))?,
+                };
+            }
+        }
+        "image/webp" => {
+            file.seek(SeekFrom::Start(12))?;
+            let mut chunk = [0; 8];
+            while fill(file, &mut chunk)? {
+                let len = u32_le(&chunk, 4).unwrap();
+                if chunk[..4] == *b"EXIF" {
+                    let mut data = read_up_to(file, u64::from(len))?;
+                    // Some programs keep the header it has in JPEG files
+                    if data.starts_with(b"Exif\0\0") {
+                        data.drain(..6);
+                    }
+                    return Ok(Some(data));
+                }
+                // Chunks are padded to an even size
+                file.seek(SeekFrom::Current(i64::from(len) + i64::from(len % 2)))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(None)
+}
+
+/// An entry of an IFD
+struct Entry {
+    tag: u32,
+    kind: u32,
+    count: usize,
+    /// Where the value is
+    at: usize,
+}
+
+enum Value {
+    Text(String),
+    Number(u32),
+    Ratio(u32, u32),
+}
+
+/// TIFF data, which is read from as needed, since the fields can be anywhere in it
+struct Tiff<R> {
+    reader: R,
+    little_endian: bool,
+}
+
+impl<R: Read + Seek> Tiff<R> {
+    fn new(reader: R) -> Option<Self> {
+        let mut tiff = Tiff {
+            reader,
+            little_endian: true,
+        };
+        tiff.little_endian = match &tiff.bytes(0, 4)?[..] {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(tiff)
+    }
+    /// The `len` bytes at `at`.
+    fn bytes(&mut self, at: usize, len: usize) -> Option<Vec<u8>> {
+        self.reader.seek(SeekFrom::Start(at as u64)).ok()?;
+        let data = read_up_to(&mut self.reader, len as u64).ok()?;
+        if data.len() == len {
+            Some(data)
+        } else {
+            None
+        }
+    }
+    fn u16(&mut self, at: usize) -> Option<u32> {
+        let data = self.bytes(at, 2)?;
+        if self.little_endian {
+            u16_le(&data, 0)
+        } else {
+            u16_be(&data, 0)
+        }
+    }
+    fn u32(&mut self, at: usize) -> Option<u32> {
+        let data = self.bytes(at, 4)?;
+        if self.little_endian {
+            u32_le(&data, 0)
+        } else {
+            u32_be(&data, 0)
+        }
+    }
+    /// The entries of the IFD at `offset`.
+    fn entries(&mut self, offset: usize) -> Option<Vec<Entry>> {
+        let count = self.u16(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let at = offset + 2 + i * 12;
+            let kind = self.u16(at + 2)?;
+            let count = self.u32(at + 4)? as usize;
+            let size = match kind {
+                3 => 2,
+                4 | 9 => 4,
+                5 | 10 => 8,
+                _ => 1,
+            };
+            // Values that fit in 4 bytes are right in the entry
+            let value_at = if size * count <= 4 {
+                at + 8
+            } else {
+                self.u32(at + 8)? as usize
+            };
+            entries.push(Entry {
+                tag: self.u16(at)?,
+                kind,
+                count,
+                at: value_at,
+            });
+        }
+        Some(entries)
+    }
+    /// The entries of the IFD that an entry points to.
+    fn sub_entries(&mut self, entry: &Entry) -> Option<Vec<Entry>> {
+        let offset = self.u32(entry.at)?;
+        self.entries(offset as usize)
+    }
+    fn value(&mut self, entry: &Entry) -> Option<Value> {
+        match entry.kind {
+            2 => {
+                let bytes = self.bytes(entry.at, entry.count)?;
+                let text = String::from_utf8_lossy(&bytes);
+                Some(Value::Text(text.trim_matches('\0').to_owned()))
+            }
+            3 => Some(Value::Number(self.u16(entry.at)?)),
+            4 => Some(Value::Number(self.u32(entry.at)?)),
+            5 => Some(Value::Ratio(self.u32(entry.at)?, self.u32(entry.at + 4)?)),
+            _ => None,
+        }
+    }
+}
+
+fn tag(name: &str, value: &str) -> String {
+    format!("{}{}:{}", PREFIX, name, value)
+}
+
+/// The tags of the field `name` with `value`.
+fn field_tags(name: &str, value: Value) -> Vec<String> {
+    match (name, value) {
+        ("date", Value::Text(text)) => {
+            // Dates look like `2017:05:03 14:22:01`, and are all zeros when unknown
+            let date = text.split_whitespace().next().unwrap_or("").replace(':', "-");
+            let is_date = date.len() == 10 && date.chars().all(|c| c == '-' || c.is_ascii_digit());
+            if !is_date || date.starts_with("0000") {
+                return Vec::new();
+            }
+            vec![
+                tag("date", &date),
+                tag("month", &date[..7]),
+                tag("year", &date[..4]),
+            ]
+        }
+        (_, Value::Text(text)) => {
+            let value = infix::sanitize_tag(&text).to_lowercase();
+            if value.is_empty() {
+                Vec::new()
+            } else {
+                vec![tag(name, &value)]
+            }
+        }
+        (_, Value::Number(n)) => vec![tag(name, &n.to_string())],
+        (_, Value::Ratio(0, _)) | (_, Value::Ratio(_, 0)) => Vec::new(),
+        (_, Value::Ratio(num, den)) => {
+            let ratio = f64::from(num) / f64::from(den);
+            let value = match name {
+                "exposure" if num < den => format!("1/{}", (1.0 / ratio).round()),
+                "exposure" => format!("{}s", ratio),
+                "aperture" => format!("f{}", (ratio * 10.0).round() / 10.0),
+                "focal" => format!("{}mm", ratio.round()),
+                _ => ratio.to_string(),
+            };
+            vec![tag(name, &value)]
+        }
+    }
+}
+
+/// The tags of the `fields` in EXIF data.
+fn tags<R: Read + Seek>(mut tiff: Tiff<R>, fields: &[&str]) -> Vec<String> {
+    let main = tiff.u32(4)
+        .and_then(|offset| tiff.entries(offset as usize))
+        .unwrap_or_default();
+    let exif = main.iter()
+        .find(|e| e.tag == EXIF_IFD)
+        .and_then(|e| tiff.sub_entries(e))
+        .unwrap_or_default();
+    let mut tags = Vec::new();
+    for &(name, ifd, field_tag) in FIELDS {
+        if !fields.contains(&name) {
+            continue;
+        }
+        let entries = if ifd == Ifd::Main { &main } else { &exif };
+        let mut entry = entries.iter().find(|e| e.tag == field_tag);
+        if name == "gps" {
+            let gps = if entry.is_some() { "yes" } else { "no" };
+            tags.push(tag(name, gps));
+            continue;
+        }
+        if name == "date" && entry.is_none() {
+            entry = main.iter().find(|e| e.tag == DATE_TIME);
+        }
+        if let Some(value) = entry.and_then(|e| tiff.value(e)) {
+            tags.extend(field_tags(name, value));
+        }
+    }
+    tags
+}
+
+/// The EXIF tags of the `fields` of the file at `path`. Files without EXIF data have none.
+pub fn file_tags(path: &Path, fields: &[&str]) -> io::Result<Vec<String>> {
+    if fs::metadata(path)?.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut file = fs::File::open(path)?;
+    let header = read_up_to(&mut file, 16)?;
+    let tags = match sniff_mime(&header) {
+        // TIFF files are EXIF data themselves
+        Some("image/tiff") => Tiff::new(file).map(|tiff| tags(tiff, fields)),
+        Some(mime) => exif_block(&mut file, mime)?
+            .and_then(|data| Tiff::new(io::Cursor::new(data)))
+            .map(|tiff| tags(tiff, fields)),
+        None => None,
+    };
+    Ok(tags.unwrap_or_default())
+}
+
+#[test]
+fn test_tags() {
+    // Little endian TIFF with a model in IFD0, and a date taken in the EXIF IFD
+    let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+    tiff.extend(b"\x02\0");
+    tiff.extend(b"\x10\x01\x02\0\x08\0\0\0\x26\0\0\0");
+    tiff.extend(b"\x69\x87\x04\0\x01\0\0\0\x2e\0\0\0");
+    tiff.extend(b"\0\0\0\0");
+    tiff.extend(b"Foo  X1\0");
+    tiff.extend(b"\x01\0");
+    tiff.extend(b"\x03\x90\x02\0\x14\0\0\0\x40\0\0\0");
+    tiff.extend(b"\0\0\0\0");
+    tiff.extend(b"2017:05:03 14:22:01\0");
+    let mut jpeg = b"\xff\xd8\xff\xe0\0\x04ab\xff\xe1".to_vec();
+    jpeg.extend(&[0, (tiff.len() + 8) as u8]);
+    jpeg.extend(b"Exif\0\0");
+    jpeg.extend(&tiff);
+    jpeg.extend(b"\xff\xda");
+    assert_eq!(
+        exif_block(&mut io::Cursor::new(jpeg), "image/jpeg").unwrap(),
+        Some(tiff.clone())
+    );
+    let tiff = Tiff::new(io::Cursor::new(tiff)).unwrap();
+    assert_eq!(
+        tags(tiff, &["model", "date", "gps"]),
+        [
+            "exif.model:foo_x1",
+            "exif.date:2017-05-03",
+            "exif.month:2017-05",
+            "exif.year:2017",
+            "exif.gps:no",
+        ]
+    );
+}
@@ -0,0 +1,388 @@
+//! File operations that keep the list in sync with the files on disk.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use tagger_map::TaggerMap;
+
+/// Error code of renaming a file to another file system
+#[cfg(unix)]
+const CROSS_DEVICE: i32 = ::libc::EXDEV;
+/// `ERROR_NOT_SAME_DEVICE`
+#[cfg(windows)]
+const CROSS_DEVICE: i32 = 17;
+
+fn is_dir<P: AsRef<Path>>(path: P) -> bool {
+    fs::metadata(path).map(|m| m.is_dir()).unwrap_or(false)
+}
+
+fn exists<P: AsRef<Path>>(path: P) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+/// A hidden name next to `path` for keeping the file at for a while.
+fn temp_name(path: &str, purpose: &str, i: usize) -> String {
+    let path = Path::new(path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".tagger-{}.{}.{}", purpose, i, name));
+    temp.to_string_lossy().into_owned()
+}
+
+/// The destination of `src` when it's moved or copied to `target`.
+///
+/// If `target` is a directory, `src` ends up inside it.
+pub fn destination(src: &str, target: &str) -> Result<String, String> {
+    if !is_dir(target) {
+        return Ok(target.to_owned());
+    }
+    let file_name = Path::new(src)
+        .file_name()
+        .ok_or_else(|| format!("{} has no file name", src))?;
+    let dst = if target == "." {
+        Path::new(file_name).to_owned()
+    } else {
+        Path::new(target).join(file_name)
+    };
+    match dst.to_str() {
+        Some(dst) => Ok(dst.to_owned()),
+        None => Err(format!("Invalid destination for {}", src)),
+    }
+}
+
+/// Work out which file goes where when moving or copying `sources` to `target`,
+/// without touching anything.
+///
+/// Existing files and entries are only allowed to be overwritten if `force` is set.
+pub fn plan_transfers(
+    list: &TaggerMap,
+    sources: &[&str],
+    target: &str,
+    force: bool,
+) -> Result<Vec<(String, String)>, String> {
+    if (sources.len() > 1 || target.ends_with('/')) && !is_dir(target) {
+        return Err(format!("{} is not a directory", target));
+    }
+    let mut transfers: Vec<(String, String)> = Vec::new();
+    for &src in sources {
+        if !list.tag_map.entries.contains_key(src) {
+            return Err(format!("{} is not in the list", src));
+        }
+        if !exists(src) {
+            return Err(format!("{} doesn't exist", src));
+        }
+        let dst = destination(src, target)?;
+        if dst == src {
+            return Err(format!("{} and {} are the same file", src, dst));
+        }
+        if transfers.iter().any(|(_, other)| *other == dst) {
+            return Err(format!("More than one file would end up at {}", dst));
+        }
+        if list.tag_map.entries.contains_key(&dst) || exists(&dst) {
+            if !force {
+                return Err(format!("{} already exists. Use --force to overwrite it.", dst));
+            }
+            if is_dir(&dst) {
+                return Err(format!("Can't overwrite directory {}", dst));
+            }
+        }
+        transfers.push((src.to_owned(), dst));
+    }
+    Ok(transfers)
+}
+
+/// Move the files at the destinations of `transfers` that are about to be overwritten out of
+/// the way, so they can be put back if the operation fails.
+///
+/// Returns where each one went, for `restore_overwritten` or `remove_overwritten`.
+pub fn move_aside(transfers: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+    let mut aside = Vec::new();
+    for (i, (_, dst)) in transfers.iter().enumerate() {
+        if !exists(dst) {
+            continue;
+        }
+        let temp = temp_name(dst, "overwritten", i);
+        let result = if exists(&temp) {
+            Err(format!("Temporary file {} is in the way", temp))
+        } else {
+            fs::rename(dst, &temp)
+                .map_err(|e| format!("Couldn't move {} out of the way: {}", dst, e))
+        };
+        if let Err(e) = result {
+            restore_overwritten(&aside);
+            return Err(e);
+        }
+        aside.push((dst.clone(), temp));
+    }
+    Ok(aside)
+}
+
+/// Put the files moved aside by `move_aside` back, after the operation failed.
+pub fn restore_overwritten(aside: &[(String, String)]) {
+    for (dst, temp) in aside {
+        if let Err(e) = fs::rename(temp, dst) {
+            eprintln!("Error moving {} back to {}: {}", temp, dst, e);
+        }
+    }
+}
+
+/// Remove the files moved aside by `move_aside` for good, after the operation succeeded.
+pub fn remove_overwritten(aside: &[(String, String)]) {
+    for (_, temp) in aside {
+        if let Err(e) = remove_recursive(Path::new(temp)) {
+            eprintln!("Error removing {}: {}", temp, e);
+        }
+    }
+}
+
+/// Move the files in `moves`. If one of them fails, the ones already moved are moved back.
+///
+/// The destinations have to be free, see `move_aside`.
+pub fn perform_moves(moves: &[(String, String)]) -> Result<(), String> {
+    for (i, (src, dst)) in moves.iter().enumerate() {
+        if let Err(e) = move_file(src, dst) {
+            for (src, dst) in moves[..i].iter().rev() {
+                if let Err(e) = move_file(dst, src) {
+                    eprintln!("Error moving {} back to {}: {}", dst, src, e);
+                }
+            }
+            return Err(format!("Couldn't move {} to {}: {}", src, dst, e));
+        }
+    }
+    Ok(())
+}
+
+/// Undo `moves` that were performed, reporting the files that can't be moved back.
+pub fn move_back(moves: &[(String, String)]) {
+    let back = moves
+        .iter()
+        .rev()
+        .map(|(src, dst)| (dst.clone(), src.clone()))
+        .collect::<Vec<_>>();
+    if let Err(e) = perform_moves(&back) {
+        eprintln!("Error: {}", e);
+    }
+}
+
+/// Update the list after the files in `moves` were moved.
+pub fn apply_moves(list: &mut TaggerMap, moves: &[(String, String)]) {
+    for (src, dst) in moves {
+        let tags = list.tag_map.entries.remove(src).unwrap_or_default();
+        list.tag_map.entries.insert(dst.clone(), tags);
+    }
+}
+
+/// Check that `renames` can be done all at once without overwriting anything.
+///
+/// Files may take the names of other files that are renamed along with them.
+/// Renames that keep the name are dropped.
+pub fn plan_renames(
+    list: &TaggerMap,
+    renames: Vec<(String, String)>,
+) -> Result<Vec<(String, String)>, String> {
+    let renames = renames
+        .into_iter()
+        .filter(|(src, dst)| src != dst)
+        .collect::<Vec<_>>();
+    let mut seen = BTreeSet::new();
+    for (src, dst) in &renames {
+        if !seen.insert(dst) {
+            return Err(format!("More than one file would be renamed to {}", dst));
+        }
+        if !exists(src) {
+            return Err(format!("{} doesn't exist", src));
+        }
+        let is_renamed = renames.iter().any(|(other, _)| other == dst);
+        if !is_renamed && (list.tag_map.entries.contains_key(dst) || exists(dst)) {
+            return Err(format!("{} would overwrite {}", src, dst));
+        }
+        let parent = Path::new(dst).parent().unwrap_or_else(|| Path::new(""));
+        if parent != Path::new("") && !is_dir(parent) {
+            return Err(format!("Directory {} doesn't exist", parent.display()));
+        }
+    }
+    Ok(renames)
+}
+
+/// Perform renames checked by `plan_renames`.
+///
+/// The files are moved to temporary names first, so they can take each other's names.
+/// Returns the moves that were done, in order.
+pub fn perform_renames(renames: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+    let to_temp = renames
+        .iter()
+        .enumerate()
+        .map(|(i, (src, _))| (src.clone(), temp_name(src, "rename", i)))
+        .collect::<Vec<_>>();
+    if let Some((_, temp)) = to_temp.iter().find(|(_, temp)| exists(temp)) {
+        return Err(format!("Temporary file {} is in the way", temp));
+    }
+    perform_moves(&to_temp)?;
+    let from_temp = to_temp
+        .iter()
+        .zip(renames)
+        .map(|((_, temp), (_, dst))| (temp.clone(), dst.clone()))
+        .collect::<Vec<_>>();
+    if let Err(e) = perform_moves(&from_temp) {
+        move_back(&to_temp);
+        return Err(e);
+    }
+    Ok(to_temp.into_iter().chain(from_temp).collect())
+}
+
+/// Copy the files in `copies`. If one of them fails, the copies already made are removed,
+/// and the files they overwrote are put back.
+pub fn perform_copies(copies: &[(String, String)]) -> Result<(), String> {
+    // Files about to be overwritten are kept until all of the copies are made
+    let aside = move_aside(copies)?;
+    let mut copied = Vec::new();
+    let mut result = Ok(());
+    for (src, dst) in copies {
+        if let Err(e) = copy_recursive(Path::new(src), Path::new(dst)) {
+            // Don't leave a partial copy behind
+            let _ = remove_recursive(Path::new(dst));
+            result = Err(format!("Couldn't copy {} to {}: {}", src, dst, e));
+            break;
+        }
+        copied.push(dst);
+    }
+    if result.is_ok() {
+        remove_overwritten(&aside);
+    } else {
+        for dst in copied {
+            let _ = remove_recursive(Path::new(dst));
+        }
+        restore_overwritten(&aside);
+    }
+    result
+}
+
+/// Update the list after the files in `copies` were copied.
+pub fn apply_copies(list: &mut TaggerMap, copies: &[(String, String)]) {
+    for (src, dst) in copies {
+        let tags = list.tag_map.entries.get(src).cloned().unwrap_or_default();
+        list.tag_map.entries.insert(dst.clone(), tags);
+    }
+}
+
+/// Rename a file, falling back to copying and removing it when moving to another file system.
+pub fn move_file<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<()> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    match fs::rename(src, dst) {
+        Err(ref e) if e.raw_os_error() == Some(CROSS_DEVICE) => {}
+        result => return result,
+    }
+    if let Err(e) = copy_recursive(src, dst) {
+        // Don't leave a partial copy behind
+        let _ = remove_recursive(dst);
+        return Err(e);
+    }
+    remove_recursive(src)
+}
+
+/// Copy a file, or a directory along with its contents.
+pub fn copy_recursive(src: &Path, dst: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(src)?.is_dir() {
+        fs::create_dir(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+        Ok(())
+    } else {
+        fs::copy(src, dst).map(|_| ())
+    }
+}
+
+/// Remove a file, or a directory along with its contents.
+pub fn remove_recursive(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// A new empty directory for a test
+#[cfg(test)]
+fn test_dir(name: &str) -> ::std::path::PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("tagger-test-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write a file for a test, returning its path.
+#[cfg(test)]
+fn test_file(dir: &Path, name: &str, contents: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_plan_transfers() {
+    let dir = test_dir("transfers");
+    let (a, b) = (test_file(&dir, "a", "A"), test_file(&dir, "b", "B"));
+    let unlisted = test_file(&dir, "unlisted", "");
+    let sub = dir.join("sub").to_string_lossy().into_owned();
+    fs::create_dir(&sub).unwrap();
+    let mut list = TaggerMap::new();
+    for name in &[&a, &b, &sub] {
+        list.tag_map.entries.insert(name.to_string(), Vec::new());
+    }
+    assert!(plan_transfers(&list, &[&a], &b, false).is_err());
+    assert_eq!(
+        plan_transfers(&list, &[&a], &b, true),
+        Ok(vec![(a.clone(), b.clone())])
+    );
+    assert_eq!(
+        plan_transfers(&list, &[&a, &b], &sub, false),
+        Ok(vec![(a.clone(), format!("{}/a", sub)), (b.clone(), format!("{}/b", sub))])
+    );
+    assert!(plan_transfers(&list, &[&a, &b], &b, true).is_err());
+    assert!(plan_transfers(&list, &[&unlisted], &sub, false).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_perform_copies_rollback() {
+    let dir = test_dir("copies");
+    let (a, b, c) = (
+        test_file(&dir, "a", "A"),
+        test_file(&dir, "b", "B"),
+        test_file(&dir, "c", "C"),
+    );
+    let missing = dir.join("missing").to_string_lossy().into_owned();
+    // The first copy overwrites b, then the second one fails
+    assert!(perform_copies(&[(a, b.clone()), (missing, c.clone())]).is_err());
+    assert_eq!(fs::read_to_string(&b).unwrap(), "B");
+    assert_eq!(fs::read_to_string(&c).unwrap(), "C");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_renames() {
+    let dir = test_dir("renames");
+    let (a, b, c) = (
+        test_file(&dir, "a", "A"),
+        test_file(&dir, "b", "B"),
+        test_file(&dir, "c", "C"),
+    );
+    let d = dir.join("d").to_string_lossy().into_owned();
+    let mut list = TaggerMap::new();
+    for name in &[&a, &b, &c] {
+        list.tag_map.entries.insert(name.to_string(), Vec::new());
+    }
+    let duplicate = vec![(a.clone(), d.clone()), (b.clone(), d.clone())];
+    assert!(plan_renames(&list, duplicate).is_err());
+    assert!(plan_renames(&list, vec![(a.clone(), c.clone())]).is_err());
+    let swap = vec![(a.clone(), b.clone()), (b.clone(), a.clone())];
+    let swap = plan_renames(&list, swap).unwrap();
+    perform_renames(&swap).unwrap();
+    assert_eq!(fs::read_to_string(&a).unwrap(), "B");
+    assert_eq!(fs::read_to_string(&b).unwrap(), "A");
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,119 @@
+//! Approximate matching of tags, for completing them and for suggesting tags in place of ones
+//! that don't exist.
+
+use std::collections::BTreeSet;
+
+/// How well `pattern` matches `candidate`, if its characters appear in it in order.
+///
+/// Runs of consecutive characters, and characters at the start of words of the candidate score
+/// higher, so `cat` matches `cat` better than `black_cat`, and that better than `cheat`.
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<usize> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    let candidate = candidate.chars().collect::<Vec<_>>();
+    let bonus = |j: usize| match j {
+        0 => 6,
+        _ if !candidate[j - 1].is_alphanumeric() => 4,
+        _ => 0,
+    };
+    // The best score of the pattern so far, if its last character is matched at each position
+    let mut best: Vec<Option<usize>> = Vec::new();
+    for (i, p) in pattern.chars().enumerate() {
+        let mut row = vec![None; candidate.len()];
+        // The best score of the previous characters matched anywhere before the current position
+        let mut before = if i == 0 { Some(0) } else { None };
+        for j in 0..candidate.len() {
+            if candidate[j] == p {
+                let consecutive = if j > 0 && i > 0 {
+                    best[j - 1].map(|s| s + 3)
+                } else {
+                    None
+                };
+                row[j] = before.max(consecutive).map(|s| s + 1 + bonus(j));
+            }
+            if i > 0 {
+                before = before.max(best[j]);
+            }
+        }
+        best = row;
+    }
+    // Matching up to the end of the candidate is a bit better
+    best.iter()
+        .enumerate()
+        .filter_map(|(j, s)| s.map(|s| if j + 1 == candidate.len() { s + 2 } else { s }))
+        .max()
+}
+
+/// The candidates matching `word` fuzzily, best matches first.
+pub fn fuzzy_matches<'a, I>(word: &str, candidates: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut matches = candidates
+        .into_iter()
+        .filter_map(|c| fuzzy_score(word, c).map(|score| (score, c)))
+        .collect::<Vec<_>>();
+    matches.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    matches.into_iter().map(|(_, c)| c.to_owned()).collect()
+}
+
+/// The number of characters that have to be inserted, deleted or replaced to turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    // Distances from the part of `a` seen so far to each prefix of `b`
+    let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous_diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let replace = previous_diagonal + if ca == b[j] { 0 } else { 1 };
+            previous_diagonal = row[j + 1];
+            row[j + 1] = replace.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Tags similar to `tag`, for suggesting in place of a tag that doesn't exist.
+///
+/// Tags a few typos away come first, then tags that `tag` is a fuzzy match of.
+pub fn similar_tags(tag: &str, known: &BTreeSet<String>) -> Vec<String> {
+    let max_distance = (tag.chars().count() / 3).max(1);
+    let mut close = known
+        .iter()
+        .map(|k| (edit_distance(tag, k), k))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect::<Vec<_>>();
+    close.sort();
+    let mut similar = close.into_iter().map(|(_, k)| k.clone()).collect::<Vec<_>>();
+    for k in fuzzy_matches(tag, known) {
+        if !similar.contains(&k) {
+            similar.push(k);
+        }
+    }
+    similar.truncate(3);
+    similar
+}
+
+#[test]
+fn test_fuzzy_matches() {
+    let tags = ["black_cat", "cheat", "cat", "dog"]
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(fuzzy_matches("cat", &tags), ["cat", "black_cat", "cheat"]);
+    assert_eq!(fuzzy_matches("bc", &tags), ["black_cat"]);
+}
+
+#[test]
+fn test_similar_tags() {
+    assert_eq!(edit_distance("catt", "cat"), 1);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    let known = ["cat", "cats", "black_cat", "dog"]
+        .iter()
+        .map(|t| t.to_string())
+        .collect();
+    assert_eq!(similar_tags("catt", &known), ["cat", "cats"]);
+    assert_eq!(similar_tags("bcat", &known), ["cat", "black_cat"]);
+}
@@ -4,26 +4,61 @@ extern crate sfml;
 use self::sfml::graphics::*;
 use self::sfml::window::*;
 use self::sfml::system::*;
-use self::image::{ImageBuffer, ImageResult, Rgba};
-use std::sync::{Arc, Mutex};
+use self::image::{ImageBuffer, Rgba};
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use config::Config;
 use tagger_map::TaggerMap;
 use infix;
+use thumbs::{Cache, Failure};
+use viewer;
+
+/// The number of frames per row, and the size of the frames in a window `window_width` wide.
+fn layout(window_width: u32, config: &Config) -> (u32, u32) {
+    let thumbnail_size = config.parse::<u32>("gui.thumbnail-size");
+    // Fit as many thumbnails in a row as possible, unless told otherwise
+    let frames_per_row = match (config.parse::<u32>("gui.columns"), thumbnail_size) {
+        (Some(columns), _) => columns,
+        (None, Some(size)) => (window_width / size).max(1),
+        (None, None) => 5,
+    };
+    // More columns than pixels would leave no room for the frames
+    (
+        frames_per_row,
+        thumbnail_size.unwrap_or((window_width / frames_per_row).max(1)),
+    )
+}
+
+/// The size of the thumbnails the gui shows.
+pub fn thumbnail_size(config: &Config) -> u32 {
+    // Only ask for the screen size when it matters, since that needs a display
+    match config.parse("gui.thumbnail-size") {
+        Some(size) => size,
+        None => layout(VideoMode::desktop_mode().width, config).1,
+    }
+}
 
 struct State {
     frames_per_row: u32,
     y_offset: f32,
+    scroll_speed: f32,
     font: Font,
     fail_texture: Texture,
     loading_texture: Texture,
     frame_size: u32,
+    /// Shown at the bottom of the window until the next key press or click
+    error: Option<String>,
 }
 
 impl State {
-    fn new(window_width: u32) -> Self {
-        let frames_per_row = 5;
+    fn new(window_width: u32, config: &Config) -> Self {
+        let (frames_per_row, frame_size) = layout(window_width, config);
         Self {
             frames_per_row,
             y_offset: 0.0,
+            scroll_speed: config.parse("gui.scroll-speed").unwrap_or(8.0),
             font: Font::from_memory(include_bytes!("../Vera.ttf")).unwrap(),
             fail_texture: Texture::from_memory(include_bytes!("../fail.png"), &Default::default())
                 .unwrap(),
@@ -31,7 +66,8 @@ impl State {
                 include_bytes!("../loading.png"),
                 &Default::default(),
             ).unwrap(),
-            frame_size: window_width / frames_per_row,
+            frame_size,
+            error: None,
         }
     }
 }
@@ -54,7 +90,21 @@ fn draw_frames(
     let frames_per_screen = (state.frames_per_row * frames_per_column) as usize;
     let row_offset = state.y_offset as u32 / frame_size;
     let skip = row_offset * state.frames_per_row;
+    let first = (skip as usize).min(frames.len());
+    let last = (first + frames_per_screen).min(frames.len());
+    thumb_loader.textures.tick += 1;
+    thumb_loader.set_visible(first, last, frames_per_screen);
     thumb_loader.write_to_frameset(frames);
+    // Load the next screen of frames ahead of time, after the ones on screen
+    let ahead = frames.iter().enumerate().skip(last).take(frames_per_screen);
+    for (index, frame) in ahead {
+        if frame.needs_loading() {
+            thumb_loader.request(&frame.name, frame_size, index);
+        }
+    }
+    thumb_loader
+        .textures
+        .enforce(frames, first, last, frames_per_screen);
     let frames = frames
         .into_iter()
         .enumerate()
@@ -65,16 +115,14 @@ fn draw_frames(
         let row = (on_screen_index as u32) / state.frames_per_row;
         let x = (column * frame_size) as f32;
         let y = (row * frame_size) as f32 - (state.y_offset % frame_size as f32);
-        if !frame.load_fail && frame.texture.is_none() {
+        if frame.needs_loading() {
             thumb_loader.request(&frame.name, frame_size, index);
         }
-        let mut sprite = Sprite::with_texture(if frame.load_fail {
-            &state.fail_texture
-        } else {
-            match frame.texture {
-                Some(ref t) => t,
-                None => &state.loading_texture,
-            }
+        thumb_loader.textures.drawn(index);
+        let mut sprite = Sprite::with_texture(match (&frame.texture, &frame.failure) {
+            (_, Some(_)) => &state.fail_texture,
+            (Some(t), _) => t,
+            (None, None) => &state.loading_texture,
         });
         sprite.set_position((x, y));
         if frame.selected {
@@ -85,6 +133,12 @@ fn draw_frames(
         text.set_position((x, y));
         text.set_fill_color(&Color::BLACK);
         target.draw(&text);
+        if let Some(ref failure) = frame.failure {
+            let mut text = Text::new(&failure.to_string(), &state.font, 10);
+            text.set_position((x, y + 12.0));
+            text.set_fill_color(&Color::RED);
+            target.draw(&text);
+        }
     }
 }
 
@@ -93,20 +147,53 @@ struct Frame {
     name: String,
     tags: Vec<String>,
     texture: Option<Texture>,
-    load_fail: bool,
+    /// Why the thumbnail couldn't be loaded
+    failure: Option<Failure>,
     selected: bool,
 }
 
+impl Frame {
+    fn needs_loading(&self) -> bool {
+        self.texture.is_none() && self.failure.is_none()
+    }
+}
+
+/// A named query that the GUI can switch between.
+struct Collection {
+    name: String,
+    /// Infix expression with saved query references already expanded
+    query: String,
+}
+
+/// The collection containing every entry, followed by a collection for each saved query.
+fn collections(tagger_map: &TaggerMap) -> Vec<Collection> {
+    let mut collections = vec![
+        Collection {
+            name: "All".to_owned(),
+            query: String::new(),
+        },
+    ];
+    for name in tagger_map.queries.keys() {
+        match tagger_map.expand_query(&format!("@{}", name)) {
+            Ok(query) => collections.push(Collection {
+                name: format!("@{}", name),
+                query,
+            }),
+            Err(e) => eprintln!("Skipping @{}: {}", name, e),
+        }
+    }
+    collections
+}
+
 fn construct_frameset(tagger_map: &TaggerMap, rule: &str) -> Result<Vec<Frame>, infix::ParseError> {
-    let rules = infix::parse_infix(rule)?;
-    let entries = tagger_map.tag_map.matching_entries(&rules);
+    let expr = infix::parse_infix(rule)?;
     let mut frameset = Vec::new();
-    for (name, tags) in entries {
+    for (name, tags) in tagger_map.matching(&expr) {
         frameset.push(Frame {
             name: name.clone(),
             tags: tags.to_owned(),
             texture: None,
-            load_fail: false,
+            failure: None,
             selected: false,
         });
     }
@@ -115,73 +202,220 @@ fn construct_frameset(tagger_map: &TaggerMap, rule: &str) -> Result<Vec<Frame>,
 
 type RgbaBuf = ImageBuffer<Rgba<u8>, Vec<u8>>;
 
-const BUSY_WITH_NONE: usize = ::std::usize::MAX;
+/// Used when `gui.loader-threads` isn't set
+const DEFAULT_LOADER_THREADS: usize = 4;
+/// Used when `gui.texture-memory` isn't set, in MiB
+const DEFAULT_TEXTURE_MEMORY: usize = 512;
 
-/// Loads images on a separate thread, one at a time.
-struct ThumbnailLoader {
-    busy_with: usize,
-    image_slot: Arc<Mutex<Option<ImageResult<RgbaBuf>>>>,
+/// Thumbnail requests and results, shared by the gui and the loading threads
+#[derive(Default)]
+struct Queue {
+    /// Names and sizes of the thumbnails to load, by frame index
+    pending: BTreeMap<usize, (String, u32)>,
+    /// Range of the frames on screen, which are loaded first
+    visible: (usize, usize),
+    done: Vec<(usize, Result<RgbaBuf, Failure>)>,
+    /// Set when the loader is dropped, to stop the threads
+    closed: bool,
+}
+
+impl Queue {
+    /// How many frames away from the screen the frame `index` is.
+    fn distance(&self, index: usize) -> usize {
+        let (start, end) = self.visible;
+        if index < start {
+            start - index
+        } else if index >= end {
+            index + 1 - end
+        } else {
+            0
+        }
+    }
+    /// Take the pending request closest to the screen.
+    fn take_next(&mut self) -> Option<(usize, (String, u32))> {
+        let start = self.visible.0;
+        let after = self.pending.range(start..).next().map(|(&i, _)| i);
+        let before = self.pending.range(..start).next_back().map(|(&i, _)| i);
+        let index = match (before, after) {
+            (Some(before), Some(after)) => if self.distance(before) < self.distance(after) {
+                before
+            } else {
+                after
+            },
+            (before, after) => after.or(before)?,
+        };
+        let request = self.pending.remove(&index).unwrap();
+        Some((index, request))
+    }
+}
+
+type Shared = Arc<(Mutex<Queue>, Condvar)>;
+
+/// Load the requested thumbnails until the loader is dropped.
+fn load_thumbnails(shared: &Shared, cache: &Cache) {
+    let (ref queue, ref condvar) = **shared;
+    loop {
+        let (index, (name, size)) = {
+            let mut queue = queue.lock().unwrap();
+            loop {
+                if queue.closed {
+                    return;
+                }
+                if let Some(request) = queue.take_next() {
+                    break request;
+                }
+                queue = condvar.wait(queue).unwrap();
+            }
+        };
+        let result = cache.thumbnail(&name, size);
+        queue.lock().unwrap().done.push((index, result));
+    }
+}
+
+/// Keeps the memory textures take within a budget, by dropping the textures of frames away from
+/// the screen that were drawn the longest time ago. They're loaded again when they're needed,
+/// from the thumbnail cache.
+struct TextureBudget {
+    /// How many bytes textures may take
+    budget: usize,
+    used: usize,
+    /// When the frames with a texture were last drawn, and how many bytes their textures take
+    textures: BTreeMap<usize, (u64, usize)>,
+    /// Number of the current frame of the window
+    tick: u64,
 }
 
-impl Default for ThumbnailLoader {
-    fn default() -> Self {
+impl TextureBudget {
+    fn new(budget: usize) -> Self {
         Self {
-            busy_with: BUSY_WITH_NONE,
-            image_slot: Default::default(),
+            budget,
+            used: 0,
+            textures: BTreeMap::new(),
+            tick: 0,
         }
     }
+    fn add(&mut self, index: usize, width: u32, height: u32) {
+        let bytes = width as usize * height as usize * 4;
+        self.used += bytes;
+        if let Some((_, old_bytes)) = self.textures.insert(index, (self.tick, bytes)) {
+            self.used -= old_bytes;
+        }
+    }
+    fn drawn(&mut self, index: usize) {
+        if let Some(texture) = self.textures.get_mut(&index) {
+            texture.0 = self.tick;
+        }
+    }
+    /// Drop textures until they fit in the budget, keeping the ones of the frames on screen
+    /// and of the frames less than `keep` frames away from them.
+    fn enforce(&mut self, frameset: &mut [Frame], start: usize, end: usize, keep: usize) {
+        if self.used <= self.budget {
+            return;
+        }
+        let mut far_away = self.textures
+            .iter()
+            .filter(|&(&i, _)| i + keep < start || i >= end + keep)
+            .map(|(&i, &(drawn, _))| (drawn, i))
+            .collect::<Vec<_>>();
+        far_away.sort();
+        for (_, index) in far_away {
+            if self.used <= self.budget {
+                break;
+            }
+            let (_, bytes) = self.textures.remove(&index).unwrap();
+            self.used -= bytes;
+            frameset[index].texture = None;
+        }
+    }
+}
+
+/// Loads thumbnails on a pool of threads, the ones of frames on screen first.
+///
+/// Every thread decodes one image at a time, so the number of threads also limits how much
+/// memory loading takes.
+struct ThumbnailLoader {
+    shared: Shared,
+    /// Frames that were requested, and haven't been written to the frameset yet
+    requested: BTreeSet<usize>,
+    textures: TextureBudget,
 }
 
 impl ThumbnailLoader {
+    fn new(cache: &Cache, threads: usize, texture_memory: usize) -> Self {
+        let shared = Shared::default();
+        for _ in 0..threads {
+            let shared = Arc::clone(&shared);
+            let cache = cache.clone();
+            thread::spawn(move || load_thumbnails(&shared, &cache));
+        }
+        Self {
+            shared,
+            requested: BTreeSet::new(),
+            textures: TextureBudget::new(texture_memory),
+        }
+    }
     fn request(&mut self, name: &str, size: u32, index: usize) {
-        if self.busy_with == BUSY_WITH_NONE {
-            self.busy_with = index;
-            let image_slot = Arc::clone(&self.image_slot);
-            let name = name.to_owned();
-            ::std::thread::spawn(move || {
-                use std::fs::File;
-                use std::io::prelude::*;
-                use self::image::FilterType;
-                let mut f = File::open(name).unwrap();
-                // Try to load file as efficiently as possible, using a single compact allocation.
-                // We trust that `len` returned by metadata is correct.
-                let len = f.metadata().unwrap().len() as usize;
-                let mut buf = Vec::with_capacity(len as usize);
-                unsafe {
-                    // Set length for `read_exact` to fill.
-                    buf.set_len(len);
-                    // This should fill all the uninitialized buffer.
-                    f.read_exact(&mut buf).unwrap();
-                }
-                // Because loading images is memory intensive, and we might load multiple images
-                // in parallel, we eagerly drop some stuff in order to free up memory
-                // as soon as possible.
-                drop(f);
-                let image_result = image::load_from_memory(&buf);
-                drop(buf);
-                let result =
-                    image_result.map(|i| i.resize(size, size, FilterType::Triangle).to_rgba());
-                *image_slot.lock().unwrap() = Some(result);
-            });
+        if self.requested.insert(index) {
+            let (ref queue, ref condvar) = *self.shared;
+            queue
+                .lock()
+                .unwrap()
+                .pending
+                .insert(index, (name.to_owned(), size));
+            condvar.notify_one();
+        }
+    }
+    /// Tell which frames are on screen, and cancel the requests of frames more than `keep`
+    /// frames away from them.
+    fn set_visible(&mut self, start: usize, end: usize, keep: usize) {
+        let mut queue = self.shared.0.lock().unwrap();
+        queue.visible = (start, end);
+        let far_away = queue
+            .pending
+            .keys()
+            .cloned()
+            .filter(|&i| queue.distance(i) > keep)
+            .collect::<Vec<_>>();
+        for index in far_away {
+            queue.pending.remove(&index);
+            self.requested.remove(&index);
         }
     }
     fn write_to_frameset(&mut self, frameset: &mut [Frame]) {
-        if let Some(result) = self.image_slot.lock().unwrap().take() {
+        let done = mem::take(&mut self.shared.0.lock().unwrap().done);
+        for (index, result) in done {
+            self.requested.remove(&index);
+            let frame = &mut frameset[index];
             match result {
                 Ok(buf) => {
                     let (w, h) = buf.dimensions();
-                    let mut tex = Texture::new(w, h).unwrap();
-                    tex.update_from_pixels(&buf.into_raw(), w, h, 0, 0);
-                    frameset[self.busy_with].texture = Some(tex);
+                    match Texture::new(w, h) {
+                        Some(mut tex) => {
+                            tex.update_from_pixels(&buf.into_raw(), w, h, 0, 0);
+                            frame.texture = Some(tex);
+                            self.textures.add(index, w, h);
+                        }
+                        None => {
+                            let reason = format!("Couldn't make a {}x{} texture", w, h);
+                            frame.failure = Some(Failure::Other(reason));
+                        }
+                    }
                 }
-                Err(_) => frameset[self.busy_with].load_fail = true,
+                Err(failure) => frame.failure = Some(failure),
             }
-            self.busy_with = BUSY_WITH_NONE;
         }
     }
 }
 
-pub fn run(tagger_map: &mut TaggerMap) {
+impl Drop for ThumbnailLoader {
+    fn drop(&mut self) {
+        let (ref queue, ref condvar) = *self.shared;
+        queue.lock().unwrap().closed = true;
+        condvar.notify_all();
+    }
+}
+
+pub fn run(tagger_map: &mut TaggerMap, config: &Config, cache: &Cache) {
     let mut window = RenderWindow::new(
         VideoMode::desktop_mode(),
         "Tagger",
@@ -190,36 +424,75 @@ pub fn run(tagger_map: &mut TaggerMap) {
     );
     window.set_framerate_limit(60);
 
-    let mut state = State::new(window.size().x);
+    let mut state = State::new(window.size().x, config);
+    let collections = collections(tagger_map);
+    let mut collection_index = 0;
     let mut frameset = construct_frameset(tagger_map, "").unwrap();
-    let mut thumb_loader = ThumbnailLoader::default();
+    let loader_threads = config
+        .parse("gui.loader-threads")
+        .unwrap_or(DEFAULT_LOADER_THREADS);
+    let texture_memory = config
+        .parse::<usize>("gui.texture-memory")
+        .unwrap_or(DEFAULT_TEXTURE_MEMORY) * 1024 * 1024;
+    let mut thumb_loader = ThumbnailLoader::new(cache, loader_threads, texture_memory);
 
     while window.is_open() {
         while let Some(event) = window.poll_event() {
             match event {
                 Event::Closed => window.close(),
-                Event::KeyPressed { code, .. } => if code == Key::PageDown {
-                    state.y_offset += window.size().y as f32;
-                } else if code == Key::PageUp {
-                    state.y_offset -= window.size().y as f32;
-                } else if code == Key::Return {
-                    let mut names: Vec<&str> = Vec::new();
-                    for f in &frameset {
-                        if f.selected {
-                            names.push(&f.name);
+                Event::KeyPressed { code, .. } => {
+                    state.error = None;
+                    if code == Key::PageDown {
+                        state.y_offset += window.size().y as f32;
+                    } else if code == Key::PageUp {
+                        state.y_offset -= window.size().y as f32;
+                    } else if code == Key::Return {
+                        let mut names: Vec<&str> = Vec::new();
+                        for f in &frameset {
+                            if f.selected {
+                                names.push(&f.name);
+                            }
+                        }
+                        if let Err(e) = viewer::open(config, &names) {
+                            state.error = Some(e);
+                        }
+                    } else if code == Key::Tab {
+                        // Cycle through the collections, backwards when shift is held
+                        let len = collections.len();
+                        collection_index = if Key::LShift.is_pressed() {
+                            (collection_index + len - 1) % len
+                        } else {
+                            (collection_index + 1) % len
+                        };
+                        match construct_frameset(tagger_map, &collections[collection_index].query) {
+                            Ok(new_frameset) => {
+                                frameset = new_frameset;
+                                state.y_offset = 0.0;
+                                // Results for the old frameset would end up in the wrong frames
+                                thumb_loader =
+                                    ThumbnailLoader::new(cache, loader_threads, texture_memory);
+                            }
+                            Err(e) => eprintln!("Error: {}", e),
                         }
                     }
-                    open_in_image_viewer(&names);
-                },
+                }
                 Event::MouseButtonPressed { button, x, y } => if button == mouse::Button::Left {
+                    state.error = None;
                     let frame_x = x as u32 / state.frame_size;
                     let frame_y = (y as u32 + state.y_offset as u32) / state.frame_size;
                     let frame_index = frame_y * state.frames_per_row + frame_x;
-                    let frame = &mut frameset[frame_index as usize];
-                    if Key::LShift.is_pressed() {
-                        frame.selected = !frame.selected;
+                    // Clicks right of the last column would land in the next row
+                    let frame = if frame_x < state.frames_per_row {
+                        frameset.get_mut(frame_index as usize)
                     } else {
-                        open_in_image_viewer(&[&frame.name]);
+                        None
+                    };
+                    if let Some(frame) = frame {
+                        if Key::LShift.is_pressed() {
+                            frame.selected = !frame.selected;
+                        } else if let Err(e) = viewer::open(config, &[&frame.name]) {
+                            state.error = Some(e);
+                        }
                     }
                 },
                 Event::MouseWheelScrolled {
@@ -232,22 +505,73 @@ pub fn run(tagger_map: &mut TaggerMap) {
                 _ => {}
             }
         }
-        let scroll_speed = 8.0;
         if Key::Down.is_pressed() {
-            state.y_offset += scroll_speed;
+            state.y_offset += state.scroll_speed;
         } else if Key::Up.is_pressed() {
-            state.y_offset -= scroll_speed;
+            state.y_offset -= state.scroll_speed;
         }
         if state.y_offset < 0.0 {
             state.y_offset = 0.0;
         }
         window.clear(&Color::BLACK);
         draw_frames(&state, &mut frameset, &mut window, &mut thumb_loader);
+        let mut text = Text::new(
+            &format!(
+                "{} ({} entries)",
+                collections[collection_index].name,
+                frameset.len()
+            ),
+            &state.font,
+            16,
+        );
+        text.set_position((4.0, 4.0));
+        text.set_fill_color(&Color::WHITE);
+        window.draw(&text);
+        if let Some(ref error) = state.error {
+            let mut text = Text::new(error, &state.font, 16);
+            text.set_position((4.0, window.size().y as f32 - 24.0));
+            text.set_fill_color(&Color::RED);
+            window.draw(&text);
+        }
         window.display();
     }
 }
 
-fn open_in_image_viewer(names: &[&str]) {
-    use std::process::Command;
-    Command::new("viewnior").args(names).spawn().unwrap();
+#[test]
+fn test_take_next() {
+    let mut queue = Queue::default();
+    for &i in &[2, 10, 17, 30] {
+        queue.pending.insert(i, (i.to_string(), 64));
+    }
+    queue.visible = (12, 20);
+    let order = (0..4)
+        .map(|_| queue.take_next().unwrap().0)
+        .collect::<Vec<_>>();
+    assert_eq!(order, [17, 10, 2, 30]);
+    assert!(queue.take_next().is_none());
+}
+
+#[test]
+fn test_texture_budget() {
+    let mut frameset = (0..10)
+        .map(|i| Frame {
+            name: i.to_string(),
+            tags: Vec::new(),
+            texture: None,
+            failure: None,
+            selected: false,
+        })
+        .collect::<Vec<_>>();
+    // Room for three 16x16 textures
+    let mut budget = TextureBudget::new(3 * 16 * 16 * 4);
+    for &i in &[0, 1, 8, 5] {
+        budget.tick += 1;
+        budget.add(i, 16, 16);
+    }
+    budget.tick += 1;
+    budget.drawn(0);
+    // Frame 5 is on screen and 8 is close to it, so 1 goes, as it was drawn longer ago than 0
+    budget.enforce(&mut frameset, 5, 6, 3);
+    assert_eq!(budget.textures.keys().cloned().collect::<Vec<_>>(), [0, 5, 8]);
+    assert_eq!(budget.used, budget.budget);
 }
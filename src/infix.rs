@@ -1,41 +1,139 @@
 use std::fmt;
-use tagmap::MatchRule;
 
 #[derive(Debug, PartialEq)]
-pub struct ParseError;
+pub struct ParseError(String);
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Unknown error.")
+        write!(f, "{}", self.0)
     }
 }
 
-/// Parse infix boolean algebra into a rule
-pub fn parse_infix(text: &str) -> Result<MatchRule<String>, ParseError> {
-    use tagmap::MatchRule::*;
-    use self::Token::*;
-    let mut rules = Vec::new();
-    let tokens = tokenize(text);
-    let mut not = false;
-    for t in tokens {
-        match t {
-            Tag(ref s) => {
-                let rule = if not {
-                    not = false;
-                    NotTags(vec![s.clone()])
-                } else {
-                    Tags(vec![s.clone()])
-                };
-                rules.push(rule);
+/// A parsed infix expression
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    Tag(String),
+    Not(Box<Expr>),
+    /// Matches if all of the expressions match, so the empty one matches everything
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+impl Expr {
+    /// Whether an entry with `tags` matches the expression.
+    pub fn matches(&self, tags: &[String]) -> bool {
+        match *self {
+            Expr::Tag(ref tag) => tags.contains(tag),
+            Expr::Not(ref expr) => !expr.matches(tags),
+            Expr::And(ref exprs) => exprs.iter().all(|e| e.matches(tags)),
+            Expr::Or(ref exprs) => exprs.iter().any(|e| e.matches(tags)),
+        }
+    }
+    /// The tags the expression refers to, along with whether they're negated.
+    pub fn tags(&self) -> Vec<(&String, bool)> {
+        match *self {
+            Expr::Tag(ref tag) => vec![(tag, false)],
+            Expr::Not(ref expr) => expr.tags()
+                .into_iter()
+                .map(|(tag, negated)| (tag, !negated))
+                .collect(),
+            Expr::And(ref exprs) | Expr::Or(ref exprs) => {
+                exprs.iter().flat_map(|e| e.tags()).collect()
             }
-            PrefixNot => not = true,
-            _ => {}
         }
     }
-    Ok(Rules(rules))
 }
 
-#[derive(Debug, PartialEq)]
+/// `text` made usable in a tag. Whitespace and operator characters would split it up in queries,
+/// so runs of them become `_`.
+pub fn sanitize_tag(text: &str) -> String {
+    let is_separator = |c: char| c.is_whitespace() || "()!&|".contains(c);
+    let words = text.split(is_separator).filter(|w| !w.is_empty());
+    words.collect::<Vec<_>>().join("_")
+}
+
+/// Parse infix boolean algebra into an expression.
+///
+/// `|` binds weaker than `&`, which can be left out between operands. `!` negates the operand
+/// after it, which can be a group in parentheses.
+pub fn parse_infix(text: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(text),
+        at: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Ok(Expr::And(Vec::new()));
+    }
+    let expr = parser.or()?;
+    if parser.at < parser.tokens.len() {
+        return Err(ParseError("Unmatched )".to_owned()));
+    }
+    Ok(expr)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.at)
+    }
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(&Token::InfixOr) {
+            self.at += 1;
+            exprs.push(self.and()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            Expr::Or(exprs)
+        })
+    }
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        use self::Token::*;
+        let mut exprs = vec![self.operand()?];
+        loop {
+            match self.peek() {
+                Some(&InfixAnd) => self.at += 1,
+                Some(&Tag(_)) | Some(&PrefixNot) | Some(&Lparen) => {}
+                _ => break,
+            }
+            exprs.push(self.operand()?);
+        }
+        Ok(if exprs.len() == 1 {
+            exprs.pop().unwrap()
+        } else {
+            Expr::And(exprs)
+        })
+    }
+    fn operand(&mut self) -> Result<Expr, ParseError> {
+        use self::Token::*;
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        match token {
+            Some(Tag(tag)) => Ok(Expr::Tag(tag)),
+            Some(PrefixNot) => Ok(Expr::Not(Box::new(self.operand()?))),
+            Some(Lparen) => {
+                let expr = self.or()?;
+                if self.peek() != Some(&Rparen) {
+                    return Err(ParseError("Missing )".to_owned()));
+                }
+                self.at += 1;
+                Ok(expr)
+            }
+            Some(Rparen) => Err(ParseError("Expected a tag before )".to_owned())),
+            Some(InfixAnd) | Some(InfixOr) => {
+                Err(ParseError("Expected a tag before & or |".to_owned()))
+            }
+            None => Err(ParseError("Expected a tag at the end".to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 enum Token {
     Lparen,
     Rparen,
@@ -50,20 +148,30 @@ fn tokenize(text: &str) -> Vec<Token> {
     let mut tokens = Vec::new();
     let mut tag = String::new();
     for c in text.chars() {
-        match c {
-            '(' => tokens.push(Lparen),
-            ')' => tokens.push(Rparen),
-            '!' => tokens.push(PrefixNot),
-            '&' => tokens.push(InfixAnd),
-            '|' => tokens.push(InfixOr),
-            _ if c.is_whitespace() => if !tag.is_empty() {
-                tokens.push(Tag(tag.clone()));
-                tag.clear();
-            },
+        let token = match c {
+            '(' => Lparen,
+            ')' => Rparen,
+            '!' => PrefixNot,
+            '&' => InfixAnd,
+            '|' => InfixOr,
+            _ if c.is_whitespace() => {
+                if !tag.is_empty() {
+                    tokens.push(Tag(tag.clone()));
+                    tag.clear();
+                }
+                continue;
+            }
             _ => {
                 tag.push(c);
+                continue;
             }
+        };
+        // A tag ends where an operator starts
+        if !tag.is_empty() {
+            tokens.push(Tag(tag.clone()));
+            tag.clear();
         }
+        tokens.push(token);
     }
     if !tag.is_empty() {
         tokens.push(Tag(tag.clone()));
@@ -72,6 +180,13 @@ fn tokenize(text: &str) -> Vec<Token> {
     tokens
 }
 
+#[test]
+fn test_sanitize_tag() {
+    assert_eq!(sanitize_tag(" Foo  (X1) "), "Foo_X1");
+    assert_eq!(sanitize_tag("R&D | !x"), "R_D_x");
+    assert_eq!(sanitize_tag(" ! "), "");
+}
+
 #[test]
 fn test_tokenize() {
     use self::Token::*;
@@ -83,11 +198,26 @@ fn test_tokenize() {
 
 #[test]
 fn test_parse() {
-    use tagmap::MatchRule::*;
+    use self::Expr::*;
+    let tag = |t: &str| Tag(t.to_owned());
     assert_eq!(
         parse_infix("foo !bar"),
-        Ok(Rules(
-            vec![Tags(vec!["foo".into()]), NotTags(vec!["bar".into()])]
-        ))
+        Ok(And(vec![tag("foo"), Not(Box::new(tag("bar")))]))
+    );
+    assert_eq!(
+        parse_infix("a & !(b | c) | d"),
+        Ok(Or(vec![
+            And(vec![tag("a"), Not(Box::new(Or(vec![tag("b"), tag("c")])))]),
+            tag("d"),
+        ]))
+    );
+    assert_eq!(parse_infix(""), Ok(And(vec![])));
+    assert!(parse_infix("(a b").is_err());
+    assert!(parse_infix("a b)").is_err());
+    assert!(parse_infix("a |").is_err());
+    let (a, b, c) = ("a".to_owned(), "b".to_owned(), "c".to_owned());
+    assert_eq!(
+        parse_infix("a !(b | !c)").unwrap().tags(),
+        [(&a, false), (&b, true), (&c, false)]
     );
 }
@@ -0,0 +1,516 @@
+//! Append-only log of the changes made to the list, which allows undoing and redoing them.
+//!
+//! Every operation is written as an `op` line followed by one line per change.
+//! Undoing and redoing an operation appends an `undo` or `redo` line referring to it,
+//! so the history itself is never rewritten.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use fileops;
+use tagger_map::TaggerMap;
+use trash;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// An entry was added with these tags
+    Add(String, Vec<String>),
+    /// An entry with these tags was removed
+    Remove(String, Vec<String>),
+    /// Tags were added to an entry
+    Tag(String, Vec<String>),
+    /// Tags were removed from an entry
+    Untag(String, Vec<String>),
+    /// A file was renamed along with its entry
+    Mv(String, String),
+    /// A saved query changed from the first value to the second one
+    Query(String, Option<String>, Option<String>),
+}
+
+impl Change {
+    pub fn inverse(&self) -> Change {
+        match *self {
+            Change::Add(ref name, ref tags) => Change::Remove(name.clone(), tags.clone()),
+            Change::Remove(ref name, ref tags) => Change::Add(name.clone(), tags.clone()),
+            Change::Tag(ref name, ref tags) => Change::Untag(name.clone(), tags.clone()),
+            Change::Untag(ref name, ref tags) => Change::Tag(name.clone(), tags.clone()),
+            Change::Mv(ref src, ref dst) => Change::Mv(dst.clone(), src.clone()),
+            Change::Query(ref name, ref old, ref new) => {
+                Change::Query(name.clone(), new.clone(), old.clone())
+            }
+        }
+    }
+    /// Apply the change to the list, and to the file system in case of a rename.
+    pub fn apply(&self, list: &mut TaggerMap) -> Result<(), String> {
+        let entries = &mut list.tag_map.entries;
+        match *self {
+            Change::Add(ref name, ref tags) => {
+                if entries.contains_key(name) {
+                    return Err(format!("{} is already in the list", name));
+                }
+                entries.insert(name.clone(), tags.clone());
+            }
+            Change::Remove(ref name, _) => if entries.remove(name).is_none() {
+                return Err(format!("{} is not in the list", name));
+            },
+            Change::Tag(ref name, ref tags) => {
+                let entry = entries
+                    .get_mut(name)
+                    .ok_or_else(|| format!("{} is not in the list", name))?;
+                for tag in tags {
+                    if !entry.contains(tag) {
+                        entry.push(tag.clone());
+                    }
+                }
+            }
+            Change::Untag(ref name, ref tags) => {
+                let entry = entries
+                    .get_mut(name)
+                    .ok_or_else(|| format!("{} is not in the list", name))?;
+                entry.retain(|t| !tags.contains(t));
+            }
+            Change::Mv(ref src, ref dst) => {
+                if !entries.contains_key(src) {
+                    return Err(format!("{} is not in the list", src));
+                }
+                if entries.contains_key(dst) || fs::metadata(dst).is_ok() {
+                    return Err(format!("{} already exists", dst));
+                }
+                fileops::move_file(src, dst)
+                    .map_err(|e| format!("Couldn't move {} to {}: {}", src, dst, e))?;
+                if let Err(e) = trash::moved(Path::new(src), Path::new(dst)) {
+                    eprintln!("Error updating the trash info of {}: {}", dst, e);
+                }
+                let tags = entries.remove(src).unwrap();
+                entries.insert(dst.clone(), tags);
+            }
+            Change::Query(ref name, _, ref new) => match *new {
+                Some(ref query) => {
+                    list.queries.insert(name.clone(), query.clone());
+                }
+                None => {
+                    list.queries.remove(name);
+                }
+            },
+        }
+        Ok(())
+    }
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "{}", self)
+    }
+    fn parse(line: &str) -> Option<Change> {
+        let (kind, rest) = (line.get(..1)?, line.get(1..)?);
+        let tags = |rest: &str| rest.split_whitespace().map(|s| s.to_owned()).collect();
+        Some(match kind {
+            "+" | "-" | "t" | "u" => {
+                let (name, rest) = quoted(rest)?;
+                match kind {
+                    "+" => Change::Add(name, tags(rest)),
+                    "-" => Change::Remove(name, tags(rest)),
+                    "t" => Change::Tag(name, tags(rest)),
+                    _ => Change::Untag(name, tags(rest)),
+                }
+            }
+            "m" => {
+                let (src, rest) = quoted(rest)?;
+                let (dst, _) = quoted(rest)?;
+                Change::Mv(src, dst)
+            }
+            "q" => {
+                let (name, rest) = quoted(rest)?;
+                let (old, rest) = optional_quoted(rest)?;
+                let (new, _) = optional_quoted(rest)?;
+                Change::Query(name, old, new)
+            }
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::Add(ref name, ref tags) => write!(f, "+ \"{}\" {}", name, tags.join(" ")),
+            Change::Remove(ref name, ref tags) => write!(f, "- \"{}\" {}", name, tags.join(" ")),
+            Change::Tag(ref name, ref tags) => write!(f, "t \"{}\" {}", name, tags.join(" ")),
+            Change::Untag(ref name, ref tags) => write!(f, "u \"{}\" {}", name, tags.join(" ")),
+            Change::Mv(ref src, ref dst) => write!(f, "m \"{}\" \"{}\"", src, dst),
+            Change::Query(ref name, ref old, ref new) => {
+                let quote = |q: &Option<String>| match *q {
+                    Some(ref q) => format!("\"{}\"", q),
+                    None => "-".to_owned(),
+                };
+                write!(f, "q \"{}\" {} {}", name, quote(old), quote(new))
+            }
+        }
+    }
+}
+
+/// Parse a `"quoted"` field, returning it along with the rest of the text.
+fn quoted(text: &str) -> Option<(String, &str)> {
+    let text = text.trim();
+    if !text.starts_with('"') {
+        return None;
+    }
+    let end = text[1..].find('"')? + 1;
+    Some((text[1..end].to_owned(), &text[end + 1..]))
+}
+
+/// Like `quoted`, but `-` stands for no value.
+fn optional_quoted(text: &str) -> Option<(Option<String>, &str)> {
+    let text = text.trim();
+    match text.strip_prefix('-') {
+        Some(rest) => Some((None, rest)),
+        None => quoted(text).map(|(s, rest)| (Some(s), rest)),
+    }
+}
+
+/// The state of the list before an operation, used to find out what the operation changed.
+pub struct Snapshot {
+    entries: BTreeMap<String, Vec<String>>,
+    queries: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    pub fn of(list: &TaggerMap) -> Self {
+        Snapshot {
+            entries: list.tag_map.entries.clone(),
+            queries: list.queries.clone(),
+        }
+    }
+    /// The list as it was when the snapshot was taken.
+    pub fn list(&self) -> TaggerMap {
+        let mut list = TaggerMap::new();
+        list.tag_map.entries = self.entries.clone();
+        list.queries = self.queries.clone();
+        list
+    }
+    /// The changes that lead from the snapshot to `list`.
+    ///
+    /// Renames can't be told apart from removing and adding an entry, so the operation has to
+    /// report them in `renames`. They come first in the returned changes, each one preceded by
+    /// the removal of the entry it overwrote, if any.
+    pub fn changes_to(&self, list: &TaggerMap, renames: Vec<Change>) -> Vec<Change> {
+        let mut before = self.entries.clone();
+        let mut changes = Vec::new();
+        for change in renames {
+            if let Change::Mv(ref src, ref dst) = change {
+                if let Some(tags) = before.remove(src) {
+                    if let Some(old_tags) = before.insert(dst.clone(), tags) {
+                        changes.push(Change::Remove(dst.clone(), old_tags));
+                    }
+                }
+            }
+            changes.push(change);
+        }
+        let after = &list.tag_map.entries;
+        for (name, tags) in &before {
+            match after.get(name) {
+                None => changes.push(Change::Remove(name.clone(), tags.clone())),
+                Some(new_tags) => {
+                    let added = new_tags.iter().filter(|t| !tags.contains(t));
+                    let added = added.cloned().collect::<Vec<_>>();
+                    if !added.is_empty() {
+                        changes.push(Change::Tag(name.clone(), added));
+                    }
+                    let removed = tags.iter().filter(|t| !new_tags.contains(t));
+                    let removed = removed.cloned().collect::<Vec<_>>();
+                    if !removed.is_empty() {
+                        changes.push(Change::Untag(name.clone(), removed));
+                    }
+                }
+            }
+        }
+        for (name, tags) in after {
+            if !before.contains_key(name) {
+                changes.push(Change::Add(name.clone(), tags.clone()));
+            }
+        }
+        let names = self.queries.keys().chain(list.queries.keys());
+        for name in names.collect::<BTreeSet<_>>() {
+            let (old, new) = (self.queries.get(name), list.queries.get(name));
+            if old != new {
+                changes.push(Change::Query(name.clone(), old.cloned(), new.cloned()));
+            }
+        }
+        changes
+    }
+}
+
+pub struct Record {
+    pub id: u64,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub description: String,
+    pub changes: Vec<Change>,
+    pub undone: bool,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    records: Vec<Record>,
+    /// Indices of records that can be undone, most recent last
+    done: Vec<usize>,
+    /// Indices of records that can be redone, most recently undone last
+    undone: Vec<usize>,
+}
+
+impl Journal {
+    /// Open the journal at `path`. A missing file means an empty journal.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut journal = Journal {
+            path: path.as_ref().to_owned(),
+            records: Vec::new(),
+            done: Vec::new(),
+            undone: Vec::new(),
+        };
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(journal),
+            Err(e) => return Err(e),
+        };
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid journal entry on line {}", i + 1),
+                )
+            };
+            let mut words = line.splitn(4, ' ');
+            match words.next() {
+                Some("op") => {
+                    let id = words.next().and_then(|s| s.parse().ok()).ok_or_else(&invalid)?;
+                    let time = words.next().and_then(|s| s.parse().ok()).ok_or_else(&invalid)?;
+                    journal.push(Record {
+                        id,
+                        time,
+                        description: words.next().unwrap_or("").to_owned(),
+                        changes: Vec::new(),
+                        undone: false,
+                    });
+                }
+                Some("undo") | Some("redo") => {
+                    let redo = line.starts_with("redo");
+                    let id = words.next().and_then(|s| s.parse::<u64>().ok());
+                    let (from, to) = if redo {
+                        (&mut journal.undone, &mut journal.done)
+                    } else {
+                        (&mut journal.done, &mut journal.undone)
+                    };
+                    let index = from.pop().ok_or_else(&invalid)?;
+                    if id != Some(journal.records[index].id) {
+                        return Err(invalid());
+                    }
+                    journal.records[index].undone = !redo;
+                    to.push(index);
+                }
+                _ => {
+                    let change = Change::parse(&line).ok_or_else(&invalid)?;
+                    let record = journal.records.last_mut().ok_or_else(&invalid)?;
+                    record.changes.push(change);
+                }
+            }
+        }
+        Ok(journal)
+    }
+    fn push(&mut self, record: Record) {
+        self.records.push(record);
+        self.done.push(self.records.len() - 1);
+        // A new operation makes the undone ones impossible to redo
+        self.undone.clear();
+    }
+    fn append<F: FnOnce(&mut fs::File) -> io::Result<()>>(&self, f: F) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        f(&mut file)
+    }
+    /// Record an operation. Operations that didn't change anything aren't recorded.
+    pub fn record(&mut self, description: &str, changes: Vec<Change>) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let record = Record {
+            id: self.records.last().map_or(1, |r| r.id + 1),
+            time,
+            description: description.to_owned(),
+            changes,
+            undone: false,
+        };
+        self.append(|file| {
+            let mut buf = Vec::new();
+            writeln!(buf, "op {} {} {}", record.id, record.time, record.description)?;
+            for change in &record.changes {
+                change.write(&mut buf)?;
+            }
+            // Write the whole record at once, so a failure doesn't leave half of it behind
+            file.write_all(&buf)
+        })?;
+        self.push(record);
+        Ok(())
+    }
+    /// Undo the most recent operation that wasn't undone yet, and save the list.
+    ///
+    /// Returns the undone record, or `None` if there is nothing to undo.
+    pub fn undo(
+        &mut self,
+        list: &mut TaggerMap,
+        list_path: &Path,
+    ) -> Result<Option<&Record>, String> {
+        self.step(list, list_path, false)
+    }
+    /// Redo the most recently undone operation, and save the list.
+    ///
+    /// Returns the redone record, or `None` if there is nothing to redo.
+    pub fn redo(
+        &mut self,
+        list: &mut TaggerMap,
+        list_path: &Path,
+    ) -> Result<Option<&Record>, String> {
+        self.step(list, list_path, true)
+    }
+    fn step(
+        &mut self,
+        list: &mut TaggerMap,
+        list_path: &Path,
+        redo: bool,
+    ) -> Result<Option<&Record>, String> {
+        let index = match if redo { self.undone.last() } else { self.done.last() } {
+            Some(&index) => index,
+            None => return Ok(None),
+        };
+        let changes = if redo {
+            self.records[index].changes.clone()
+        } else {
+            self.records[index].changes.iter().rev().map(Change::inverse).collect()
+        };
+        // Roll back what was already applied on failure, so the list stays consistent
+        let rollback = |applied: &[Change], list: &mut TaggerMap| {
+            for change in applied.iter().rev() {
+                if let Err(e) = change.inverse().apply(list) {
+                    eprintln!("Error rolling back `{}`: {}", change, e);
+                }
+            }
+        };
+        for (i, change) in changes.iter().enumerate() {
+            let result = match *change {
+                // Putting back an entry whose file is gone would leave it without one
+                Change::Add(ref name, _) if fs::symlink_metadata(name).is_err() => Err(format!(
+                    "{} doesn't exist anymore, so its entry can't be put back",
+                    name
+                )),
+                _ => change.apply(list),
+            };
+            if let Err(e) = result {
+                rollback(&changes[..i], list);
+                return Err(e);
+            }
+        }
+        if let Err(e) = list.save_to_file(list_path) {
+            rollback(&changes, list);
+            return Err(format!("Couldn't save {}: {}", list_path.display(), e));
+        }
+        let id = self.records[index].id;
+        let word = if redo { "redo" } else { "undo" };
+        self.append(|file| writeln!(file, "{} {}", word, id))
+            .map_err(|e| format!("Couldn't write to journal: {}", e))?;
+        if redo {
+            self.undone.pop();
+            self.done.push(index);
+        } else {
+            self.done.pop();
+            self.undone.push(index);
+        }
+        self.records[index].undone = !redo;
+        Ok(Some(&self.records[index]))
+    }
+    /// All recorded operations, oldest first.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time.
+pub fn format_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let secs = secs % 86_400;
+    // Civil date from days since epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[test]
+fn test_change_roundtrip() {
+    let changes = vec![
+        Change::Add("a file.png".into(), vec!["cat".into(), "cute".into()]),
+        Change::Untag("b.png".into(), vec!["dog".into()]),
+        Change::Mv("c.png".into(), "d e.png".into()),
+        Change::Query("favs".into(), None, Some("best !nsfw".into())),
+    ];
+    for change in changes {
+        assert_eq!(Change::parse(&change.to_string()), Some(change));
+    }
+}
+
+#[test]
+fn test_changes_to_overwritten_entry() {
+    let mut list = TaggerMap::new();
+    list.tag_map.entries.insert("a.png".into(), vec!["cat".into()]);
+    list.tag_map.entries.insert("b.png".into(), vec!["dog".into()]);
+    let before = Snapshot::of(&list);
+    let tags = list.tag_map.entries.remove("a.png").unwrap();
+    list.tag_map.entries.insert("b.png".into(), tags);
+    let rename = Change::Mv("a.png".into(), "b.png".into());
+    assert_eq!(
+        before.changes_to(&list, vec![rename.clone()]),
+        [Change::Remove("b.png".into(), vec!["dog".into()]), rename]
+    );
+}
+
+#[test]
+fn test_undo_missing_file() {
+    let dir = ::std::env::temp_dir().join(format!("tagger-test-journal-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let name = dir.join("gone.png").to_string_lossy().into_owned();
+    let list_path = dir.join("tagger.list");
+    let mut journal = Journal::open(dir.join("tagger.journal")).unwrap();
+    journal
+        .record("rm", vec![Change::Remove(name.clone(), vec!["dog".into()])])
+        .unwrap();
+    let mut list = TaggerMap::new();
+    assert!(journal.undo(&mut list, &list_path).is_err());
+    assert!(list.tag_map.entries.is_empty());
+    assert!(!list_path.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_format_time() {
+    assert_eq!(format_time(0), "1970-01-01 00:00:00");
+    assert_eq!(format_time(1_508_360_461), "2017-10-18 21:01:01");
+}
@@ -0,0 +1,232 @@
+//! Locating the list, and translating between entry names and paths on the command line.
+//!
+//! Entry names are relative to the directory of the list, which becomes the working directory
+//! once the list is located. Paths given on the command line are relative to the directory
+//! tagger was started from.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+pub struct Library {
+    /// The list file, relative to the library root
+    pub list_file: PathBuf,
+    /// The directory containing the list
+    pub root: PathBuf,
+    /// The directory tagger was started from
+    pub invocation_dir: PathBuf,
+}
+
+impl Library {
+    /// Find the lists, and if there's only one, make its directory the working directory.
+    ///
+    /// The lists are `paths` if given, otherwise the ones in the `TAGGER_LIST` environment
+    /// variable, separated like in `PATH`, otherwise the nearest list in the current directory
+    /// or its parents.
+    /// If `create` is set, the list doesn't have to exist, and it's never searched for in
+    /// parent directories.
+    pub fn locate(paths: &[&str], create: bool) -> io::Result<Vec<Self>> {
+        let invocation_dir = env::current_dir()?.canonicalize()?;
+        let mut explicit = paths.iter().map(PathBuf::from).collect::<Vec<_>>();
+        if explicit.is_empty() {
+            if let Some(value) = env::var_os("TAGGER_LIST") {
+                explicit.extend(env::split_paths(&value).filter(|p| !p.as_os_str().is_empty()));
+            }
+        }
+        let libraries = if explicit.is_empty() {
+            let list_path = if create {
+                invocation_dir.join(::LIST_DEFAULT_FILENAME)
+            } else {
+                find_list(&invocation_dir).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "No {} in this directory or its parents",
+                            ::LIST_DEFAULT_FILENAME
+                        ),
+                    )
+                })?
+            };
+            vec![Library::at(list_path, &invocation_dir)?]
+        } else {
+            explicit
+                .into_iter()
+                .map(|path| {
+                    let path = invocation_dir.join(path);
+                    if path.is_dir() {
+                        Library::at(path.join(::LIST_DEFAULT_FILENAME), &invocation_dir)
+                    } else {
+                        Library::at(path, &invocation_dir)
+                    }
+                })
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        if libraries.len() == 1 {
+            env::set_current_dir(&libraries[0].root)?;
+        }
+        Ok(libraries)
+    }
+    fn at(list_path: PathBuf, invocation_dir: &Path) -> io::Result<Self> {
+        let list_file = match list_path.file_name() {
+            Some(name) => PathBuf::from(name),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid list path: {}", list_path.display()),
+                ))
+            }
+        };
+        let root = list_path.parent().unwrap_or_else(|| Path::new("/"));
+        let root = fs::canonicalize(root)?;
+        Ok(Library {
+            list_file,
+            root,
+            invocation_dir: invocation_dir.to_owned(),
+        })
+    }
+    /// The list file, usable from any working directory.
+    pub fn list_path(&self) -> PathBuf {
+        self.root.join(&self.list_file)
+    }
+    /// The path of the list's own file `name`, one of the `TAGGER_FILENAMES`.
+    pub fn own_file(&self, name: &str) -> PathBuf {
+        self.root.join(own_file_name(&self.list_file, name))
+    }
+    /// How an entry is referred to when working with `count` libraries at once.
+    ///
+    /// A single library is the working directory, so its entry names can be used as they are.
+    /// Otherwise, entries are referred to by their absolute path.
+    pub fn entry_key(&self, name: &str, count: usize) -> String {
+        if count == 1 {
+            name.to_owned()
+        } else {
+            self.root.join(name).to_string_lossy().into_owned()
+        }
+    }
+    /// The entry name of a path given on the command line.
+    ///
+    /// A trailing `/` is kept, since it means the path has to be a directory.
+    pub fn entry_name(&self, path: &str) -> String {
+        let full_path = normalize(&self.invocation_dir.join(path));
+        let mut name = match full_path.strip_prefix(&self.root) {
+            Ok(rel) if rel == Path::new("") => ".".to_owned(),
+            Ok(rel) => rel.to_string_lossy().into_owned(),
+            Err(_) => full_path.to_string_lossy().into_owned(),
+        };
+        if path.ends_with('/') && !name.ends_with('/') {
+            name.push('/');
+        }
+        name
+    }
+    /// The path of an entry key in the `output.format` setting's `format`.
+    pub fn output_path(&self, key: &str, format: &str) -> String {
+        match format {
+            "absolute" => self.root.join(key).to_string_lossy().into_owned(),
+            "name" => Path::new(key)
+                .strip_prefix(&self.root)
+                .unwrap_or_else(|_| Path::new(key))
+                .to_string_lossy()
+                .into_owned(),
+            _ => self.display_path(key),
+        }
+    }
+    /// The path of an entry, or of an entry key, for printing to the user.
+    pub fn display_path(&self, name: &str) -> String {
+        if self.root == self.invocation_dir && Path::new(name).is_relative() {
+            return name.to_owned();
+        }
+        let path = relative_path(&self.invocation_dir, &self.root.join(name));
+        if path == Path::new("") {
+            return ".".to_owned();
+        }
+        path.to_string_lossy().into_owned()
+    }
+}
+
+/// The name of the own file `name` of the list `list_file`, like its journal.
+///
+/// The default list's files are named like `tagger.journal`. Other lists have theirs named after
+/// the list, like `photos.list.journal`, so lists in the same directory don't share them.
+pub fn own_file_name(list_file: &Path, name: &str) -> PathBuf {
+    if list_file == Path::new(::LIST_DEFAULT_FILENAME) {
+        return PathBuf::from(name);
+    }
+    let kind = name.find('.').map_or(name, |i| &name[i + 1..]);
+    let mut file_name = list_file.as_os_str().to_owned();
+    file_name.push(".");
+    file_name.push(kind);
+    PathBuf::from(file_name)
+}
+
+fn find_list(dir: &Path) -> Option<PathBuf> {
+    let mut dir = Some(dir);
+    while let Some(d) = dir {
+        let path = d.join(::LIST_DEFAULT_FILENAME);
+        if path.is_file() {
+            return Some(path);
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+/// Resolve `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// The path of `to` relative to the directory `from`. Both paths have to be absolute.
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let to = normalize(to);
+    let common = from.components()
+        .zip(to.components())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let mut rel = PathBuf::new();
+    for _ in from.components().skip(common) {
+        rel.push("..");
+    }
+    for component in to.components().skip(common) {
+        rel.push(component.as_os_str());
+    }
+    rel
+}
+
+#[test]
+fn test_own_file_name() {
+    assert_eq!(
+        own_file_name(Path::new("tagger.list"), ::JOURNAL_FILENAME),
+        PathBuf::from("tagger.journal")
+    );
+    assert_eq!(
+        own_file_name(Path::new("photos.list"), ::JOURNAL_FILENAME),
+        PathBuf::from("photos.list.journal")
+    );
+}
+
+#[test]
+fn test_relative_path() {
+    assert_eq!(
+        relative_path(Path::new("/pics/cats"), Path::new("/pics/dogs/./a.png")),
+        PathBuf::from("../dogs/a.png")
+    );
+    assert_eq!(
+        relative_path(Path::new("/pics"), Path::new("/pics/cats/a.png")),
+        PathBuf::from("cats/a.png")
+    );
+    assert_eq!(
+        normalize(Path::new("/pics/cats/../a.png")),
+        PathBuf::from("/pics/a.png")
+    );
+}
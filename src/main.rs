@@ -1,138 +1,579 @@
+#[macro_use]
 extern crate clap;
+extern crate image;
+#[cfg(unix)]
+extern crate libc;
 #[cfg(feature = "random")]
 extern crate rand;
+extern crate regex;
 extern crate rustyline;
 extern crate tagmap;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use infix::parse_infix;
-use rustyline::Editor;
-use rustyline::completion::Completer;
-use std::cell::RefCell;
-use std::collections::BTreeSet;
-use std::env;
-use std::process::Command;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use config::Config;
+use journal::{Change, Journal, Snapshot};
+use library::Library;
+use rules::Rules;
 use tagger_map::TaggerMap;
 
 mod tagger_map;
 mod infix;
+mod saved_queries;
+mod completion;
+mod fuzzy;
+mod shell;
+mod journal;
+mod fileops;
+mod trash;
+mod template;
+mod library;
+mod config;
+mod add_tags;
+mod term_preview;
+mod autotag;
+mod exif;
+mod rules;
+mod thumbs;
+#[cfg(feature = "random")]
+mod random;
 #[cfg(feature = "gui")]
 mod gui;
+#[cfg(feature = "gui")]
+mod viewer;
 
 pub const LIST_DEFAULT_FILENAME: &str = "tagger.list";
+/// Entries picked by `random --no-repeat` in the current cycle
+pub const RANDOM_HISTORY_FILENAME: &str = "tagger.random-history";
+/// Command history of the interactive shell
+pub const SHELL_HISTORY_FILENAME: &str = "tagger.shell-history";
+/// Record of the changes made to the list, for undo and redo
+pub const JOURNAL_FILENAME: &str = "tagger.journal";
+/// Settings of the library, overriding the user's
+pub const LIBRARY_CONFIG_FILENAME: &str = "tagger.config";
+/// Rules that tag entries by their names
+pub const RULES_FILENAME: &str = "tagger.rules";
+/// Directory of cached thumbnails
+pub const THUMBNAILS_DIRNAME: &str = "tagger.thumbnails";
+/// Files used by tagger itself, which are never added to the list.
+///
+/// Lists other than the default one have them named after the list, see `Library::own_file`.
+pub const TAGGER_FILENAMES: &[&str] = &[
+    LIST_DEFAULT_FILENAME,
+    RANDOM_HISTORY_FILENAME,
+    SHELL_HISTORY_FILENAME,
+    JOURNAL_FILENAME,
+    LIBRARY_CONFIG_FILENAME,
+    RULES_FILENAME,
+    THUMBNAILS_DIRNAME,
+];
+
+/// Save the list, and record the changes made since `before` in the journal.
+///
+/// Renames done by the operation have to be given in `renames`.
+/// Failing to write the journal is only reported, so an error means the list wasn't saved.
+pub fn save_list(
+    library: &Library,
+    list: &TaggerMap,
+    before: &Snapshot,
+    description: &str,
+    renames: Vec<Change>,
+) -> Result<(), String> {
+    let journal_path = library.own_file(JOURNAL_FILENAME);
+    let mut journal = Journal::open(&journal_path)
+        .map_err(|e| format!("Couldn't open {}: {}", journal_path.display(), e))?;
+    list.save_to_file(&library.list_file)
+        .map_err(|e| format!("Couldn't save {}: {}", library.list_file.display(), e))?;
+    if let Err(e) = journal.record(description, before.changes_to(list, renames)) {
+        // Put the old list back, so the journal doesn't miss any change to it
+        if let Err(e) = before.list().save_to_file(&library.list_file) {
+            eprintln!("Error restoring {}: {}", library.list_file.display(), e);
+        }
+        return Err(format!("Couldn't write to {}: {}", journal_path.display(), e));
+    }
+    Ok(())
+}
 
-struct TagCompleter {
-    tags: BTreeSet<String>,
+/// Ask the user a yes or no question. Anything but yes is taken as no.
+fn confirm(question: &str) -> bool {
+    use std::io::{self, Write};
+    print!("{} [y/N] ", question);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().starts_with('y')
 }
 
-impl TagCompleter {
-    fn new(tags: BTreeSet<String>) -> Self {
-        TagCompleter { tags: tags }
+/// Sort entries that are in name order by another key.
+fn sort_entries(entries: &mut Vec<(&String, &Vec<String>)>, key: &str) -> Result<(), String> {
+    match key {
+        "name" => {}
+        "mtime" => sort_by_metadata(entries, |m| m.modified().ok()),
+        "size" => sort_by_metadata(entries, |m| Some(m.len())),
+        "tagcount" => entries.sort_by_key(|&(_, tags)| tags.len()),
+        "random" => {
+            #[cfg(feature = "random")]
+            {
+                use rand::{thread_rng, Rng};
+                thread_rng().shuffle(entries);
+            }
+            #[cfg(not(feature = "random"))]
+            return Err("Random sorting requires the `random` feature.".into());
+        }
+        // clap only lets the keys above through
+        _ => unreachable!(),
     }
+    Ok(())
 }
 
-struct TagCompleterRefCell(RefCell<TagCompleter>);
+/// Sort entries by a key derived from the metadata of their files.
+///
+/// Entries whose file can't be queried sort first.
+fn sort_by_metadata<K, F>(entries: &mut Vec<(&String, &Vec<String>)>, key_fn: F)
+where
+    K: Ord,
+    F: Fn(&fs::Metadata) -> Option<K>,
+{
+    let mut keyed = entries
+        .drain(..)
+        .map(|e| (fs::metadata(e.0).ok().and_then(|m| key_fn(&m)), e))
+        .collect::<Vec<_>>();
+    keyed.sort_by(|a, b| a.0.cmp(&b.0));
+    entries.extend(keyed.into_iter().map(|(_, e)| e));
+}
 
-impl Completer for TagCompleterRefCell {
-    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
-        // Beginning of word is either space before it, or 0
-        let begin = line.rfind(' ').map_or(0, |p| p + 1);
-        let tags = &self.0.borrow().tags;
-        let word = &line[begin..pos];
-        let mut candidates = Vec::new();
-        for t in tags {
-            if t.starts_with(word) {
-                candidates.push(t.to_owned());
-            }
-        }
-        Ok((begin, candidates))
+/// The values of a global argument, which may have been given after any of the subcommands.
+fn global_values<'a>(matches: &'a ArgMatches, name: &str) -> Vec<&'a str> {
+    match matches.values_of(name) {
+        Some(values) => values.collect(),
+        None => matches
+            .subcommand()
+            .1
+            .map_or(Vec::new(), |matches| global_values(matches, name)),
     }
 }
 
+/// Whether a global flag was given, before or after any of the subcommands.
+fn global_flag(matches: &ArgMatches, name: &str) -> bool {
+    matches.is_present(name)
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|matches| global_flag(matches, name))
+}
+
+/// The entries matching `query` in every library, along with the library they're from.
+///
+/// The entries are named by `Library::entry_key`.
+/// Tags that no entry of any library has are reported, or are an error if `strict` is set.
+fn select_entries(
+    libraries: &[Library],
+    query: &str,
+    strict: bool,
+) -> Result<Vec<(usize, String, Vec<String>)>, String> {
+    let mut lists = Vec::new();
+    for library in libraries {
+        let list_path = library.list_path();
+        let list = TaggerMap::from_file(&list_path)
+            .map_err(|e| format!("Couldn't open {}: {}", list_path.display(), e))?;
+        lists.push(list);
+    }
+    let lists = lists.iter().collect::<Vec<_>>();
+    let selected = tagger_map::select_in(&lists, query, strict)?;
+    let entries = selected
+        .into_iter()
+        .map(|(i, name, tags)| (i, libraries[i].entry_key(name, libraries.len()), tags.clone()))
+        .collect();
+    Ok(entries)
+}
+
+/// Show or change settings.
+fn run_config(matches: &ArgMatches, library: Option<&Library>) -> i32 {
+    let library_config = library.map(|library| library.own_file(LIBRARY_CONFIG_FILENAME));
+    if let Some(matches) = matches.subcommand_matches("get") {
+        let config = match Config::load(library_config.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        let key = matches.value_of("KEY").unwrap();
+        match config.get(key) {
+            Some(value) => println!("{}", value),
+            None => return 1,
+        }
+    } else if matches.subcommand_matches("list").is_some() {
+        let config = match Config::load(library_config.as_deref()) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        for &(key, description) in config::KEYS {
+            let mut values = config
+                .values()
+                .iter()
+                .filter(|&(k, _)| k == key || (key == "viewer.EXT" && k.starts_with("viewer.")))
+                .peekable();
+            if values.peek().is_none() {
+                println!("# {}: {}", key, description);
+            }
+            for (key, value) in values {
+                println!("{} = {}", key, value);
+            }
+        }
+    } else {
+        let (matches, value) = match matches.subcommand() {
+            ("set", Some(matches)) => (matches, matches.value_of("VALUE")),
+            (_, Some(matches)) => (matches, None),
+            _ => unreachable!(),
+        };
+        let key = matches.value_of("KEY").unwrap();
+        // Unknown settings can still be unset, since they make loading the config fail
+        if let Some(value) = value {
+            if let Err(e) = config::validate(key, value) {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+        let path = if matches.is_present("library") {
+            match library_config {
+                Some(path) => path,
+                None => {
+                    eprintln!("Error: Not in a library");
+                    return 1;
+                }
+            }
+        } else {
+            match config::user_config_path() {
+                Some(path) => path,
+                None => {
+                    eprintln!("Error: Couldn't find the config directory, HOME is not set");
+                    return 1;
+                }
+            }
+        };
+        if let Err(e) = config::write_value(&path, key, value) {
+            eprintln!("Error saving {}: {}", path.display(), e);
+            return 1;
+        }
+    }
+    0
+}
+
 fn run() -> i32 {
     let mut app = App::new("tagger");
     app = app.setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("list")
+                .long("list")
+                .global(true)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("PATH")
+                .help(
+                    "The list to use. Defaults to $TAGGER_LIST, or the nearest tagger.list in \
+                     the current directory or its parents. filt and random can be given more \
+                     than one list.",
+                ),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .global(true)
+                .help("Make tags in queries that no entry has an error, instead of a warning"),
+        )
         .subcommand(SubCommand::with_name("gen"))
         .subcommand(SubCommand::with_name("update"))
-        .subcommand(SubCommand::with_name("filt").args_from_usage("[TAGS]..."))
         .subcommand(
-            SubCommand::with_name("add-tags").arg(
-                Arg::with_name("TOOL")
-                    .short("w")
-                    .long("with")
-                    .required(true)
-                    .takes_value(true)
-                    .value_name("TOOL"),
-            ),
+            SubCommand::with_name("filt")
+                .args_from_usage(
+                    "[TAGS]...
+                     -r, --reverse 'Reverse the order of the results'
+                     -c, --count 'Only print the number of matching entries, ignoring --limit \
+                                  and --offset'
+                     -l, --limit=[N] 'Print at most N entries'
+                     -o, --offset=[N] 'Skip the first N entries'",
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .short("s")
+                        .long("sort")
+                        .takes_value(true)
+                        .possible_values(&["name", "mtime", "size", "tagcount", "random"])
+                        .help("Sort the results by this key (default is name)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("add-tags")
+                .arg(
+                    Arg::with_name("TOOL")
+                        .short("w")
+                        .long("with")
+                        .takes_value(true)
+                        .value_name("TOOL")
+                        .help(
+                            "Tool to show the entries with. Defaults to the add-tags.tool \
+                             setting, or showing them in the terminal.",
+                        ),
+                )
+                .args_from_usage(
+                    "--where=[QUERY] 'Tag the entries matching QUERY'
+                     --retag 'Also visit entries that already have tags'
+                     --send=[COMMAND] 'Keep the tool running, and show the next entry with \
+                                       COMMAND. {pid} and {file} are replaced in it.'",
+                ),
         )
         .subcommand(
             SubCommand::with_name("mv")
-                .arg(Arg::with_name("src").required(true))
-                .arg(Arg::with_name("dst").required(true)),
+                .arg(Arg::with_name("src").required(true).multiple(true))
+                .arg(Arg::with_name("dst").required(true))
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Overwrite existing files"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .about("Copy files along with their tags. The last file is the destination.")
+                .args_from_usage(
+                    "<FILES>...
+                     -w, --where=[QUERY] 'Copy the entries matching QUERY'
+                     -f, --force 'Overwrite existing files'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove files along with their entries")
+                .args_from_usage(
+                    "[FILES]...
+                     -w, --where=[QUERY] 'Remove the entries matching QUERY'
+                     -t, --trash 'Move the files to the trash instead of deleting them'
+                     -y, --yes 'Don't ask before removing the entries matching QUERY, or \
+                                directories'",
+                ),
         )
-        .subcommand(SubCommand::with_name("list-tags"));
+        .subcommand(
+            SubCommand::with_name("rename")
+                .arg(
+                    Arg::with_name("template")
+                        .short("t")
+                        .long("template")
+                        .required(true)
+                        .takes_value(true)
+                        .help(
+                            "New file name, with {tag:NAMESPACE}, {index}, {index:03}, {name} \
+                             and {ext} placeholders",
+                        ),
+                )
+                .args_from_usage(
+                    "-w, --where=[QUERY] 'Rename the entries matching QUERY'
+                     -n, --dry-run 'Only show what would be renamed'
+                     -y, --yes 'Rename without asking for confirmation'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("autotag")
+                .about("Tag entries with properties of their files, in auto.* namespaces")
+                .args_from_usage(
+                    "-w, --where=[QUERY] 'Tag the entries matching QUERY'
+                     -n, --dry-run 'Only show the tags that would change'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("exif")
+                .about("Tag photos with their EXIF data, in exif.* namespaces")
+                .args_from_usage(
+                    "-w, --where=[QUERY] 'Tag the entries matching QUERY'
+                     -n, --dry-run 'Only show the tags that would change'",
+                )
+                .after_help(
+                    "Dates are tagged by day, month and year, like exif.date:2017-05-03, \
+                     exif.month:2017-05 and exif.year:2017. Queries match them exactly, so \
+                     there's no way to select a range of dates.",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply-rules")
+                .about("Tag entries by the patterns in tagger.rules")
+                .args_from_usage(
+                    "-w, --where=[QUERY] 'Tag the entries matching QUERY'
+                     -n, --dry-run 'Only show the tags that would be added'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("thumbs")
+                .about("Put thumbnails of entries into the thumbnail cache of the gui")
+                .args_from_usage(
+                    "-w, --where=[QUERY] 'Make thumbnails of the entries matching QUERY'
+                     -s, --size=[PIXELS] 'Size of the thumbnails (default: the size the gui uses)'
+                     --clean 'Remove thumbnails of other sizes, and of changed or missing \
+                              entries. Ignores --where: the thumbnails of all entries at the \
+                              size are kept'",
+                ),
+        )
+        .subcommand(SubCommand::with_name("list-tags"))
+        .subcommand(SubCommand::with_name("shell"))
+        .subcommand(SubCommand::with_name("undo"))
+        .subcommand(SubCommand::with_name("redo"))
+        .subcommand(SubCommand::with_name("log").args_from_usage(
+            "-n, --count=[N] 'Show the N most recent operations'
+             -v, --verbose 'Show the changes each operation made'",
+        ))
+        .subcommand(
+            SubCommand::with_name("query")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("save")
+                        .arg(Arg::with_name("NAME").required(true))
+                        .arg(Arg::with_name("QUERY").required(true).multiple(true)),
+                )
+                .subcommand(SubCommand::with_name("rm").arg(Arg::with_name("NAME").required(true)))
+                .subcommand(SubCommand::with_name("list")),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("get").arg(Arg::with_name("KEY").required(true)))
+                .subcommand(SubCommand::with_name("set").args_from_usage(
+                    "<KEY>
+                     <VALUE>
+                     --library 'Change the setting for the library only'",
+                ))
+                .subcommand(SubCommand::with_name("unset").args_from_usage(
+                    "<KEY>
+                     --library 'Change the setting for the library only'",
+                ))
+                .subcommand(SubCommand::with_name("list")),
+        );
     if cfg!(feature = "gui") {
         app = app.subcommand(SubCommand::with_name("gui"));
     }
     if cfg!(feature = "random") {
-        app = app.subcommand(SubCommand::with_name("random").args_from_usage("[TAGS]..."));
+        app = app.subcommand(SubCommand::with_name("random").args_from_usage(
+            "[TAGS]...
+             -n, --count=[N] 'Pick N different entries (default 1)'
+             -w, --weight=[TAG] 'Weight entries by the value of their TAG:value tag'
+             --no-repeat 'Skip entries picked earlier, until every match was picked once'
+             --seed=[SEED] 'Seed the random generator, for reproducible picks'",
+        ));
     }
     let matches = app.get_matches();
+    if let Some(matches) = matches.subcommand_matches("config") {
+        // The settings can be changed outside of libraries too
+        let library = Library::locate(&global_values(matches, "list"), false)
+            .ok()
+            .map(|mut libraries| libraries.remove(0));
+        return run_config(matches, library.as_ref());
+    }
+    let creating = matches.subcommand_matches("gen").is_some();
+    // With a single list, this also makes its directory the working directory
+    let libraries = match Library::locate(&global_values(&matches, "list"), creating) {
+        Ok(libraries) => libraries,
+        Err(e) => {
+            eprintln!("Error locating list: {}", e);
+            return 1;
+        }
+    };
+    match matches.subcommand_name() {
+        Some("filt") | Some("random") => {}
+        Some(name) if libraries.len() > 1 => {
+            eprintln!("Error: {} only works with a single list", name);
+            return 1;
+        }
+        _ => {}
+    }
+    let library = &libraries[0];
+    let list_file = &library.list_file;
+    let strict = global_flag(&matches, "strict");
+    let config = match Config::load(Some(&library.own_file(LIBRARY_CONFIG_FILENAME))) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+    };
+    let output_format = config.get("output.format").unwrap_or("relative");
     macro_rules! load_map {
         () => {
-            match TaggerMap::from_file(LIST_DEFAULT_FILENAME) {
+            match TaggerMap::from_file(list_file) {
                 Ok(list) => list,
                 Err(e) => {
-                    eprintln!( "Error opening {}: {}", LIST_DEFAULT_FILENAME, e);
+                    eprintln!( "Error opening {}: {}", list_file.display(), e);
                     return 1;
                 }
             }
         }
     }
-    macro_rules! parse_rule {
-        ($matches:expr) => {{
-            let expr = match $matches.values_of("TAGS") {
-                Some(tags) => tags.collect::<Vec<_>>().join(" "),
-                None => String::new(),
-            };
-            match parse_infix(&expr) {
-                Ok(rule) => rule,
+    macro_rules! load_rules {
+        () => {
+            match Rules::load(&library.own_file(RULES_FILENAME)) {
+                Ok(rules) => rules,
                 Err(e) => {
-                    eprintln!( "Error parsing infix expression: {}", e);
+                    eprintln!("Error: {}", e);
                     return 1;
                 }
             }
-        }}
+        }
+    }
+    macro_rules! save_list {
+        ($list:expr, $before:expr, $description:expr) => {
+            save_list!($list, $before, $description, Vec::new())
+        };
+        ($list:expr, $before:expr, $description:expr, $renames:expr) => {
+            if let Err(e) = save_list(library, &$list, &$before, $description, $renames) {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+    }
+    macro_rules! query {
+        ($matches:expr) => {
+            match $matches.values_of("TAGS") {
+                Some(tags) => tags.collect::<Vec<_>>().join(" "),
+                None => String::new(),
+            }
+        }
     }
     if matches.subcommand_matches("gen").is_some() {
         // TODO: Only allow gen if tagger.list doesn't exist.
         // Use "update" subcommand to update existing list.
         // Use --force to generate new list anyway.
-        if std::fs::metadata(LIST_DEFAULT_FILENAME).is_ok() {
+        if std::fs::metadata(list_file).is_ok() {
             eprintln!(
                 "Error: {} already exists. Use `update` subcommand to update an existing \
                  list.",
-                LIST_DEFAULT_FILENAME
+                list_file.display()
             );
             return 1;
         }
+        let rules = load_rules!();
         let mut list = TaggerMap::new();
-        if let Err(e) = list.update_from_dir(env::current_dir().unwrap()) {
-            eprintln!("Error: {}", e);
-            return 1;
-        }
-        list.save_to_file(LIST_DEFAULT_FILENAME).unwrap();
-    } else if matches.subcommand_matches("update").is_some() {
-        let mut list = match TaggerMap::from_file(LIST_DEFAULT_FILENAME) {
-            Ok(list) => list,
+        let before = Snapshot::of(&list);
+        match list.update_from_dir(&library.root, list_file, &config.ignore_patterns()) {
+            Ok(added) => {
+                rules.apply(&mut list, &added);
+            }
             Err(e) => {
-                eprintln!("Error opening {}: {}", LIST_DEFAULT_FILENAME, e);
+                eprintln!("Error: {}", e);
                 return 1;
             }
-        };
-        match list.update_from_dir(env::current_dir().unwrap()) {
-            Ok(count) => if count > 0 {
-                println!("Added {} entries.", count);
+        }
+        save_list!(list, before, "gen");
+    } else if matches.subcommand_matches("update").is_some() {
+        let rules = load_rules!();
+        let mut list = load_map!();
+        let before = Snapshot::of(&list);
+        match list.update_from_dir(&library.root, list_file, &config.ignore_patterns()) {
+            Ok(added) => if !added.is_empty() {
+                rules.apply(&mut list, &added);
+                println!("Added {} entries.", added.len());
             } else {
                 println!("Already up to date.");
             },
@@ -141,85 +582,647 @@ fn run() -> i32 {
                 return 1;
             }
         }
-        list.save_to_file(LIST_DEFAULT_FILENAME).unwrap();
+        save_list!(list, before, "update");
     } else if let Some(matches) = matches.subcommand_matches("filt") {
-        let list = load_map!();
-        let rule = parse_rule!(matches);
-        for entry in list.tag_map.matching(&rule) {
-            println!("{}", entry);
+        let selected = match select_entries(&libraries, &query!(matches), strict) {
+            Ok(selected) => selected,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        if matches.is_present("count") {
+            println!("{}", selected.len());
+            return 0;
+        }
+        let mut entries = selected
+            .iter()
+            .map(|(_, name, tags)| (name, tags))
+            .collect::<Vec<_>>();
+        if let Some(key) = matches.value_of("sort") {
+            if let Err(e) = sort_entries(&mut entries, key) {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+        if matches.is_present("reverse") {
+            entries.reverse();
+        }
+        let offset = if matches.is_present("offset") {
+            value_t_or_exit!(matches, "offset", usize)
+        } else {
+            0
+        };
+        let limit = if matches.is_present("limit") {
+            value_t_or_exit!(matches, "limit", usize)
+        } else {
+            entries.len()
+        };
+        let origins = selected
+            .iter()
+            .map(|&(i, ref name, _)| (name, i))
+            .collect::<BTreeMap<_, _>>();
+        for (name, _) in entries.into_iter().skip(offset).take(limit) {
+            println!("{}", libraries[origins[name]].output_path(name, output_format));
         }
     } else if let Some(matches) = matches.subcommand_matches("random") {
         #[cfg(feature = "random")]
         {
-            use rand::{thread_rng, Rng};
+            use rand::{SeedableRng, StdRng};
+            use std::path::Path;
 
-            let list = load_map!();
-            let rule = parse_rule!(matches);
-            let matching = list.tag_map.matching(&rule).collect::<Vec<_>>();
-            if let Some(choice) = thread_rng().choose(&matching) {
-                println!("{}", choice);
+            let selected = match select_entries(&libraries, &query!(matches), strict) {
+                Ok(selected) => selected,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            };
+            let matching = selected
+                .iter()
+                .map(|(_, name, tags)| (name, tags))
+                .collect::<Vec<_>>();
+            let count = if matches.is_present("count") {
+                value_t_or_exit!(matches, "count", usize)
+            } else {
+                1
+            };
+            let weight_tag = matches.value_of("weight");
+            let mut rng = if matches.is_present("seed") {
+                StdRng::from_seed(&[value_t_or_exit!(matches, "seed", usize)][..])
+            } else {
+                match StdRng::new() {
+                    Ok(rng) => rng,
+                    Err(e) => {
+                        eprintln!("Error initializing random generator: {}", e);
+                        return 1;
+                    }
+                }
+            };
+            let picks = if matches.is_present("no-repeat") {
+                // Every list has its own history, which is combined while picking. The library
+                // of each entry is kept, so it goes back to its own history even when libraries
+                // are nested.
+                let mut history = BTreeSet::new();
+                let mut owners = BTreeSet::new();
+                for (i, library) in libraries.iter().enumerate() {
+                    let path = library.own_file(RANDOM_HISTORY_FILENAME);
+                    match random::load_history(&path) {
+                        Ok(names) => for name in names {
+                            let key = library.entry_key(&name, libraries.len());
+                            owners.insert((key.clone(), i));
+                            history.insert(key);
+                        },
+                        Err(e) => {
+                            eprintln!("Error opening {}: {}", path.display(), e);
+                            return 1;
+                        }
+                    }
+                }
+                owners.extend(selected.iter().map(|&(i, ref key, _)| (key.clone(), i)));
+                let picks =
+                    random::pick_no_repeat(&mut rng, matching, count, weight_tag, &mut history);
+                for (i, library) in libraries.iter().enumerate() {
+                    let path = library.own_file(RANDOM_HISTORY_FILENAME);
+                    let names = owners
+                        .iter()
+                        .filter(|&&(ref key, owner)| owner == i && history.contains(key))
+                        .map(|(key, _)| {
+                            let name = Path::new(key).strip_prefix(&library.root);
+                            name.unwrap_or_else(|_| Path::new(key))
+                                .to_string_lossy()
+                                .into_owned()
+                        })
+                        .collect();
+                    if let Err(e) = random::save_history(&path, &names) {
+                        eprintln!("Error saving {}: {}", path.display(), e);
+                        return 1;
+                    }
+                }
+                picks
+            } else {
+                random::pick(&mut rng, matching, count, weight_tag)
+            };
+            let origins = selected
+                .iter()
+                .map(|&(i, ref name, _)| (name, i))
+                .collect::<BTreeMap<_, _>>();
+            for name in picks {
+                println!("{}", libraries[origins[name]].output_path(name, output_format));
             }
         }
     } else if let Some(matches) = matches.subcommand_matches("add-tags") {
-        let tool_path = matches.value_of("TOOL").unwrap();
-        let mut taggermap = match TaggerMap::from_file(LIST_DEFAULT_FILENAME) {
-            Ok(taggermap) => taggermap,
+        let tool_path = matches.value_of("TOOL").or_else(|| config.get("add-tags.tool"));
+        let mut list = load_map!();
+        let names = match list.select(matches.value_of("where").unwrap_or(""), strict) {
+            Ok(names) => names,
             Err(e) => {
-                eprintln!("Error opening {}: {}", LIST_DEFAULT_FILENAME, e);
+                eprintln!("Error: {}", e);
                 return 1;
             }
         };
-        let completer = TagCompleterRefCell(RefCell::new(TagCompleter::new(taggermap.tags())));
-        let mut editor = Editor::new();
-        editor.set_completer(Some(&completer));
-        for (k, v) in &mut taggermap.tag_map.entries {
-            if v.is_empty() {
-                let mut cmd = Command::new(tool_path).arg(k).spawn().unwrap();
-                let line = editor.readline(&format!("Tags for {}: ", k)).unwrap();
-                cmd.kill().unwrap();
-                for word in line.split_whitespace() {
-                    v.push(word.to_owned());
-                    completer.0.borrow_mut().tags.insert(word.to_owned());
-                }
-                editor.add_history_entry(&line);
+        let retag = matches.is_present("retag");
+        let names = names
+            .into_iter()
+            .filter(|name| retag || list.tag_map.entries[name].is_empty())
+            .collect::<Vec<_>>();
+        let send = matches.value_of("send").or_else(|| config.get("add-tags.send"));
+        let protocol = term_preview::Protocol::select(config.get("add-tags.preview"));
+        match add_tags::run(&mut list, library, &names, tool_path, send, protocol) {
+            Ok(changed) => println!("Changed the tags of {} entries.", changed),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
             }
         }
-        taggermap.save_to_file(LIST_DEFAULT_FILENAME).unwrap();
     } else if let Some(matches) = matches.subcommand_matches("mv") {
-        let src = matches.value_of("src").unwrap();
-        let dst = matches.value_of("dst").unwrap();
-        let mut list = match TaggerMap::from_file(LIST_DEFAULT_FILENAME) {
-            Ok(list) => list,
+        let sources = matches
+            .values_of("src")
+            .unwrap()
+            .map(|s| library.entry_name(s))
+            .collect::<Vec<_>>();
+        let sources = sources.iter().map(|s| &s[..]).collect::<Vec<_>>();
+        let dst = library.entry_name(matches.value_of("dst").unwrap());
+        let dst = &dst[..];
+        let mut list = load_map!();
+        let force = matches.is_present("force");
+        let moves = match fileops::plan_transfers(&list, &sources, dst, force) {
+            Ok(moves) => moves,
             Err(e) => {
-                eprintln!("Error opening {}: {}", LIST_DEFAULT_FILENAME, e);
+                eprintln!("Error: {}", e);
                 return 1;
             }
         };
-        let value = list.tag_map
-            .entries
-            .remove(src)
-            .expect("Didn't find entry.");
-        list.tag_map.entries.insert(dst.to_owned(), value);
-        std::fs::rename(src, dst).unwrap();
-        list.save_to_file(LIST_DEFAULT_FILENAME).unwrap();
-    } else if matches.subcommand_matches("list-tags").is_some() {
-        let list = match TaggerMap::from_file(LIST_DEFAULT_FILENAME) {
-            Ok(list) => list,
+        let before = Snapshot::of(&list);
+        // Overwritten files are kept until the list is saved, so they can be put back
+        let aside = match fileops::move_aside(&moves) {
+            Ok(aside) => aside,
             Err(e) => {
-                eprintln!("Error opening {}: {}", LIST_DEFAULT_FILENAME, e);
+                eprintln!("Error: {}", e);
                 return 1;
             }
         };
+        if let Err(e) = fileops::perform_moves(&moves) {
+            eprintln!("Error: {}", e);
+            fileops::restore_overwritten(&aside);
+            return 1;
+        }
+        fileops::apply_moves(&mut list, &moves);
+        let renames = moves
+            .iter()
+            .map(|(src, dst)| Change::Mv(src.clone(), dst.clone()))
+            .collect();
+        let description = format!("mv {} {}", sources.join(" "), dst);
+        if let Err(e) = save_list(library, &list, &before, &description, renames) {
+            eprintln!("Error: {}", e);
+            // Move the files back, so they match the list on disk again
+            fileops::move_back(&moves);
+            fileops::restore_overwritten(&aside);
+            return 1;
+        }
+        fileops::remove_overwritten(&aside);
+    } else if let Some((command, matches)) = ["autotag", "exif"]
+        .iter()
+        .filter_map(|&c| matches.subcommand_matches(c).map(|m| (c, m)))
+        .next()
+    {
+        let mut list = load_map!();
+        let names = match list.select(matches.value_of("where").unwrap_or(""), strict) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        let (prefix, fields) = if command == "exif" {
+            let fields = config.get("exif.fields").unwrap_or(exif::DEFAULT_FIELDS);
+            (exif::PREFIX, fields.split_whitespace().collect())
+        } else {
+            (autotag::PREFIX, Vec::new())
+        };
+        let before = Snapshot::of(&list);
+        let mut changed = 0;
+        for name in names {
+            let path = std::path::Path::new(&name);
+            let found = if command == "exif" {
+                exif::file_tags(path, &fields)
+            } else {
+                autotag::file_tags(path)
+            };
+            let found = match found {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", name, e);
+                    continue;
+                }
+            };
+            let tags = list.tag_map.entries.get_mut(&name).unwrap();
+            let new_tags = autotag::replace_tags(tags, prefix, found);
+            if new_tags != *tags {
+                if matches.is_present("dry-run") {
+                    println!("{}: {}", name, new_tags.join(" "));
+                }
+                *tags = new_tags;
+                changed += 1;
+            }
+        }
+        if matches.is_present("dry-run") {
+            println!("Would update the tags of {} entries.", changed);
+        } else {
+            save_list!(list, before, command);
+            println!("Updated the tags of {} entries.", changed);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("apply-rules") {
+        let rules = load_rules!();
+        if rules.is_empty() {
+            eprintln!(
+                "Error: There are no rules in {}",
+                library.own_file(RULES_FILENAME).display()
+            );
+            return 1;
+        }
+        let mut list = load_map!();
+        let names = match list.select(matches.value_of("where").unwrap_or(""), strict) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        if matches.is_present("dry-run") {
+            let additions = rules.preview(&list, &names);
+            for (name, tags) in &additions {
+                rules::print_additions(name, tags);
+            }
+            println!("Would add tags to {} entries.", additions.len());
+        } else {
+            let before = Snapshot::of(&list);
+            let changed = rules.apply(&mut list, &names);
+            save_list!(list, before, "apply-rules");
+            println!("Added tags to {} entries.", changed);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("thumbs") {
+        let list = load_map!();
+        let names = match list.select(matches.value_of("where").unwrap_or(""), strict) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        let size = if matches.is_present("size") {
+            value_t_or_exit!(matches, "size", u32)
+        } else {
+            // Unless gui.thumbnail-size is set, the gui size depends on the screen
+            #[cfg(feature = "gui")]
+            let gui_size = Some(gui::thumbnail_size(&config));
+            #[cfg(not(feature = "gui"))]
+            let gui_size = config.parse("gui.thumbnail-size");
+            match gui_size {
+                Some(size) => size,
+                None => {
+                    eprintln!("Error: Give the size with --size, or set gui.thumbnail-size");
+                    return 1;
+                }
+            }
+        };
+        if size == 0 {
+            eprintln!("Error: The size of the thumbnails can't be 0");
+            return 1;
+        }
+        let cache = thumbs::Cache::new(&library.root);
+        if matches.is_present("clean") {
+            // Thumbnails of entries that don't match the query are kept
+            let all = list.tag_map.entries.keys().cloned().collect::<Vec<_>>();
+            match cache.clean(&all, size) {
+                Ok(removed) => println!("Removed {} thumbnails.", removed),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            }
+            return 0;
+        }
+        let (mut made, mut failed) = (0, 0);
+        for (i, name) in names.iter().enumerate() {
+            if cache.contains(name, size) {
+                continue;
+            }
+            println!("[{}/{}] {}", i + 1, names.len(), name);
+            match cache.thumbnail(name, size) {
+                Ok(_) => made += 1,
+                Err(e) => {
+                    eprintln!("Couldn't make a thumbnail of {}: {}", name, e);
+                    failed += 1;
+                }
+            }
+        }
+        println!(
+            "Made {} thumbnails, {} were already cached, {} failed.",
+            made,
+            names.len() - made - failed,
+            failed
+        );
+    } else if let Some(matches) = matches.subcommand_matches("rename") {
+        let mut list = load_map!();
+        let template = matches.value_of("template").unwrap();
+        let names = match list.select(matches.value_of("where").unwrap_or(""), strict) {
+            Ok(names) => names,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        let mut renames = Vec::new();
+        for (i, name) in names.into_iter().enumerate() {
+            let tags = &list.tag_map.entries[&name];
+            let new_name = match template::render(template, &name, tags, i + 1) {
+                Ok(new_name) => new_name,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            };
+            let dst = std::path::Path::new(&name).with_file_name(new_name);
+            renames.push((name, dst.to_string_lossy().into_owned()));
+        }
+        let renames = match fileops::plan_renames(&list, renames) {
+            Ok(renames) => renames,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        if renames.is_empty() {
+            println!("Nothing to rename.");
+            return 0;
+        }
+        for (src, dst) in &renames {
+            println!("{} -> {}", src, dst);
+        }
+        if matches.is_present("dry-run") {
+            return 0;
+        }
+        if !matches.is_present("yes") && !confirm(&format!("Rename {} files?", renames.len())) {
+            return 1;
+        }
+        let before = Snapshot::of(&list);
+        let moves = match fileops::perform_renames(&renames) {
+            Ok(moves) => moves,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        fileops::apply_moves(&mut list, &moves);
+        let changes = moves
+            .iter()
+            .map(|(src, dst)| Change::Mv(src.clone(), dst.clone()))
+            .collect();
+        let description = format!("rename {}", template);
+        if let Err(e) = save_list(library, &list, &before, &description, changes) {
+            eprintln!("Error: {}", e);
+            // Move the files back, so they match the list on disk again
+            fileops::move_back(&moves);
+            return 1;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("cp") {
+        let mut list = load_map!();
+        let mut files = matches
+            .values_of("FILES")
+            .unwrap()
+            .map(|s| library.entry_name(s))
+            .collect::<Vec<_>>();
+        let dst = files.pop().unwrap();
+        let dst = &dst[..];
+        let mut sources = files.iter().map(|s| &s[..]).collect::<Vec<_>>();
+        let selected = match matches.value_of("where") {
+            Some(query) => match list.select(query, strict) {
+                Ok(names) => names,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            },
+            None => Vec::new(),
+        };
+        sources.extend(selected.iter().map(|s| &s[..]));
+        if sources.is_empty() {
+            eprintln!("Error: Nothing to copy.");
+            return 1;
+        }
+        let force = matches.is_present("force");
+        let copies = match fileops::plan_transfers(&list, &sources, dst, force) {
+            Ok(copies) => copies,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        };
+        let before = Snapshot::of(&list);
+        if let Err(e) = fileops::perform_copies(&copies) {
+            eprintln!("Error: {}", e);
+            return 1;
+        }
+        fileops::apply_copies(&mut list, &copies);
+        save_list!(list, before, &format!("cp {} {}", sources.join(" "), dst));
+    } else if let Some(matches) = matches.subcommand_matches("rm") {
+        if !matches.is_present("FILES") && !matches.is_present("where") {
+            eprintln!("Error: Give the FILES to remove, or a QUERY with --where");
+            return 1;
+        }
+        let mut list = load_map!();
+        let mut names = matches
+            .values_of("FILES")
+            .map_or(Vec::new(), |v| v.map(|s| library.entry_name(s)).collect());
+        if let Some(query) = matches.value_of("where") {
+            match list.select(query, strict) {
+                Ok(selected) => names.extend(selected),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        if let Some(name) = names.iter().find(|n| !list.tag_map.entries.contains_key(*n)) {
+            eprintln!("Error: {} is not in the list", name);
+            return 1;
+        }
+        // Queries and directories can take a lot with them, so they're shown first
+        let is_dir = |name: &String| {
+            fs::symlink_metadata(name).map(|m| m.is_dir()).unwrap_or(false)
+        };
+        let ask = matches.is_present("where") || names.iter().any(&is_dir);
+        if ask && !matches.is_present("yes") {
+            for name in &names {
+                let note = if is_dir(name) { " (directory)" } else { "" };
+                println!("{}{}", library.display_path(name), note);
+            }
+            let question = if matches.is_present("trash") {
+                format!("Move {} files to the trash?", names.len())
+            } else {
+                format!("Remove {} files?", names.len())
+            };
+            if !confirm(&question) {
+                return 1;
+            }
+        }
+        let before = Snapshot::of(&list);
+        let mut renames = Vec::new();
+        let mut status = 0;
+        for name in &names {
+            let path = std::path::Path::new(name);
+            // Entries whose file is already gone are just removed from the list
+            let result = if fs::symlink_metadata(path).is_err() {
+                Ok(())
+            } else if matches.is_present("trash") {
+                // Recording the trashing as a rename allows undoing it
+                trash::trash(path).map(|trashed| {
+                    let trashed = trashed.to_string_lossy().into_owned();
+                    renames.push(Change::Mv(name.clone(), trashed));
+                })
+            } else {
+                fileops::remove_recursive(path)
+            };
+            if let Err(e) = result {
+                // Keep the list in sync with the files that were already removed
+                eprintln!("Error removing {}: {}", name, e);
+                status = 1;
+                break;
+            }
+            println!("Removed {}", name);
+            list.tag_map.entries.remove(name);
+        }
+        let description = if matches.is_present("trash") {
+            format!("rm --trash {}", names.join(" "))
+        } else {
+            format!("rm {}", names.join(" "))
+        };
+        save_list!(list, before, &description, renames);
+        return status;
+    } else if matches.subcommand_matches("list-tags").is_some() {
+        let list = load_map!();
         let tags = list.tags();
         for tag in tags {
             println!("{}", tag);
         }
+    } else if matches.subcommand_matches("shell").is_some() {
+        let mut list = load_map!();
+        shell::run(&mut list, library);
+    } else if let Some(matches) = matches.subcommand_matches("query") {
+        let mut list = load_map!();
+        let before = Snapshot::of(&list);
+        let description;
+        if let Some(matches) = matches.subcommand_matches("save") {
+            let name = matches.value_of("NAME").unwrap();
+            let query = matches.values_of("QUERY").unwrap().collect::<Vec<_>>().join(" ");
+            if !saved_queries::valid_name(name) {
+                eprintln!("Error: Invalid query name: {}", name);
+                return 1;
+            }
+            description = format!("query save {} {}", name, query);
+            list.queries.insert(name.to_owned(), query);
+            // Make sure the query is valid, and doesn't end up referring to itself
+            match list.expand_query(&format!("@{}", name)) {
+                Ok(expr) => if let Err(e) = parse_infix(&expr) {
+                    eprintln!("Error parsing infix expression: {}", e);
+                    return 1;
+                },
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return 1;
+                }
+            }
+        } else if let Some(matches) = matches.subcommand_matches("rm") {
+            let name = matches.value_of("NAME").unwrap();
+            if list.queries.remove(name).is_none() {
+                eprintln!("Error: No saved query named {}", name);
+                return 1;
+            }
+            description = format!("query rm {}", name);
+            for (other, query) in &list.queries {
+                if let Err(e) = list.expand_query(query) {
+                    eprintln!("Error: @{} can't be removed, @{} needs it: {}", name, other, e);
+                    return 1;
+                }
+            }
+        } else if matches.subcommand_matches("list").is_some() {
+            for (name, query) in &list.queries {
+                println!("@{}: {}", name, query);
+            }
+            return 0;
+        } else {
+            unreachable!()
+        }
+        save_list!(list, before, &description);
+    } else if let Some(name) = ["undo", "redo"]
+        .iter()
+        .find(|&&name| matches.subcommand_matches(name).is_some())
+    {
+        let mut list = load_map!();
+        let journal_path = library.own_file(JOURNAL_FILENAME);
+        let mut journal = match Journal::open(&journal_path) {
+            Ok(journal) => journal,
+            Err(e) => {
+                eprintln!("Error opening {}: {}", journal_path.display(), e);
+                return 1;
+            }
+        };
+        let result = if *name == "undo" {
+            journal.undo(&mut list, list_file)
+        } else {
+            journal.redo(&mut list, list_file)
+        };
+        match result {
+            Ok(Some(record)) => println!(
+                "{} {}: {}",
+                if *name == "undo" { "Undid" } else { "Redid" },
+                record.id,
+                record.description
+            ),
+            Ok(None) => println!("Nothing to {}.", name),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return 1;
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("log") {
+        let journal_path = library.own_file(JOURNAL_FILENAME);
+        let journal = match Journal::open(&journal_path) {
+            Ok(journal) => journal,
+            Err(e) => {
+                eprintln!("Error opening {}: {}", journal_path.display(), e);
+                return 1;
+            }
+        };
+        let records = journal.records();
+        let count = if matches.is_present("count") {
+            value_t_or_exit!(matches, "count", usize)
+        } else {
+            records.len()
+        };
+        for record in records.iter().rev().take(count) {
+            println!(
+                "{:>4} {} {}{}",
+                record.id,
+                journal::format_time(record.time),
+                record.description,
+                if record.undone { " (undone)" } else { "" }
+            );
+            if matches.is_present("verbose") {
+                for change in &record.changes {
+                    println!("       {}", change);
+                }
+            }
+        }
     } else if matches.subcommand_matches("gui").is_some() {
         #[cfg(feature = "random")]
-        match TaggerMap::from_file(LIST_DEFAULT_FILENAME) {
-            Ok(mut map) => gui::run(&mut map),
+        match TaggerMap::from_file(list_file) {
+            Ok(mut map) => gui::run(&mut map, &config, &thumbs::Cache::new(&library.root)),
             Err(e) => {
-                eprintln!("Error opening {}: {}", LIST_DEFAULT_FILENAME, e);
+                eprintln!("Error opening {}: {}", list_file.display(), e);
                 return 1;
             }
         }
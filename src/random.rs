@@ -0,0 +1,134 @@
+use rand::Rng;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// The weight of an entry, which is the numeric value of its `tag:value` tag.
+///
+/// Entries without such a tag have a weight of 1.
+fn weight(tags: &[String], weight_tag: &str) -> f64 {
+    let prefix = format!("{}:", weight_tag);
+    tags.iter()
+        .filter(|t| t.starts_with(&prefix))
+        .filter_map(|t| t[prefix.len()..].parse::<f64>().ok())
+        .next()
+        .map_or(1.0, |w| w.max(0.0))
+}
+
+/// Pick up to `n` different entries at random.
+///
+/// If `weight_tag` is given, entries are weighted by the value of that tag.
+pub fn pick<'a, R: Rng>(
+    rng: &mut R,
+    mut candidates: Vec<(&'a String, &'a Vec<String>)>,
+    n: usize,
+    weight_tag: Option<&str>,
+) -> Vec<&'a String> {
+    let mut weights = candidates
+        .iter()
+        .map(|&(_, tags)| weight_tag.map_or(1.0, |t| weight(tags, t)))
+        .collect::<Vec<_>>();
+    let mut picks = Vec::new();
+    while picks.len() < n && !candidates.is_empty() {
+        let total: f64 = weights.iter().sum();
+        let index = if total > 0.0 {
+            let mut x = rng.gen::<f64>() * total;
+            let mut index = weights.len() - 1;
+            for (i, &w) in weights.iter().enumerate() {
+                if x < w {
+                    index = i;
+                    break;
+                }
+                x -= w;
+            }
+            index
+        } else {
+            // Only zero weight entries are left, treat them equally
+            rng.gen_range(0, candidates.len())
+        };
+        picks.push(candidates.swap_remove(index).0);
+        weights.swap_remove(index);
+    }
+    picks
+}
+
+/// Like `pick`, but doesn't pick entries that are in `history`, and records the picks in it.
+///
+/// Once every candidate has been picked, a new cycle starts for them. Entries in the history
+/// that aren't candidates stay there, so other queries keep their cycles.
+pub fn pick_no_repeat<'a, R: Rng>(
+    rng: &mut R,
+    candidates: Vec<(&'a String, &'a Vec<String>)>,
+    n: usize,
+    weight_tag: Option<&str>,
+    history: &mut BTreeSet<String>,
+) -> Vec<&'a String> {
+    let (fresh, seen): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|&(name, _)| !history.contains(name));
+    let mut picks = pick(rng, fresh, n, weight_tag);
+    if picks.len() < n {
+        for &(name, _) in &seen {
+            history.remove(name);
+        }
+        let more = pick(rng, seen, n - picks.len(), weight_tag);
+        picks.extend(more);
+    }
+    // The fresh picks are recorded too, so they aren't repeated right at the start of the cycle
+    history.extend(picks.iter().map(|s| s.to_string()));
+    picks
+}
+
+/// Load the names of previously picked entries. A missing file means an empty history.
+pub fn load_history<P: AsRef<Path>>(path: P) -> io::Result<BTreeSet<String>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(e) => return Err(e),
+    };
+    BufReader::new(file).lines().collect()
+}
+
+pub fn save_history<P: AsRef<Path>>(path: P, history: &BTreeSet<String>) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    for name in history {
+        writeln!(writer, "{}", name)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_pick_no_repeat() {
+    use rand::{SeedableRng, StdRng};
+    let entries = ["a", "b", "c"]
+        .iter()
+        .map(|s| (s.to_string(), Vec::new()))
+        .collect::<Vec<_>>();
+    let candidates = || entries.iter().map(|(k, v)| (k, v)).collect();
+    let mut rng = StdRng::from_seed(&[42][..]);
+    let mut history = BTreeSet::new();
+    let mut first = pick_no_repeat(&mut rng, candidates(), 2, None, &mut history);
+    first.extend(pick_no_repeat(&mut rng, candidates(), 1, None, &mut history));
+    first.sort();
+    assert_eq!(first, ["a", "b", "c"]);
+    // A new cycle begins
+    assert_eq!(
+        pick_no_repeat(&mut rng, candidates(), 2, None, &mut history).len(),
+        2
+    );
+    assert_eq!(history.len(), 2);
+    // The last fresh candidate is remembered in the new cycle, and other entries are kept
+    let mut history = ["a", "b", "z"].iter().map(|s| s.to_string()).collect();
+    let picks = pick_no_repeat(&mut rng, candidates(), 2, None, &mut history);
+    assert_eq!(picks[0], "c");
+    assert!(history.contains("c") && history.contains("z"));
+    assert_eq!(history.len(), 3);
+}
+
+#[test]
+fn test_weight() {
+    let tags = vec!["cat".to_owned(), "rating:3".to_owned()];
+    assert_eq!(weight(&tags, "rating"), 3.0);
+    assert_eq!(weight(&tags, "score"), 1.0);
+}
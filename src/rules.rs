@@ -0,0 +1,225 @@
+//! Tagging entries by patterns in their names.
+//!
+//! The rules file of a library has `PATTERN => TAGS` lines. Patterns are regular expressions,
+//! or globs when they start with `glob:`. Tags can refer to the capture groups of the regular
+//! expression by name or number, like `{year}` or `{1}`:
+//!
+//! ```text
+//! # 2017-05-03_beach_IMG001.jpg
+//! ^(?P<year>\d{4})-(?P<month>\d{2})- => year:{year} month:{year}-{month}
+//! glob:holidays/* => holiday
+//! ```
+//!
+//! Lines starting with `#` are comments.
+
+use config;
+use infix;
+use regex::{Captures, Regex};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use tagger_map::TaggerMap;
+
+enum Pattern {
+    Regex(Regex),
+    Glob(String),
+}
+
+struct Rule {
+    pattern: Pattern,
+    tags: Vec<String>,
+}
+
+/// The placeholders in a tag template, by their position
+fn placeholders(template: &str) -> Result<Vec<(usize, usize)>, String> {
+    let mut placeholders = Vec::new();
+    let mut at = 0;
+    while let Some(start) = template[at..].find('{') {
+        let start = at + start;
+        let end = match template[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("Unclosed {{ in {}", template)),
+        };
+        placeholders.push((start, end));
+        at = end + 1;
+    }
+    Ok(placeholders)
+}
+
+/// The tag made from `template` with the placeholders replaced by the captured text.
+///
+/// There's none if a group it refers to didn't capture anything.
+fn expand(template: &str, captures: &Captures) -> Option<String> {
+    let mut tag = String::new();
+    let mut at = 0;
+    for (start, end) in placeholders(template).ok()? {
+        let group = &template[start + 1..end];
+        let text = match group.parse() {
+            Ok(i) => captures.get(i),
+            Err(_) => captures.name(group),
+        }?;
+        let text = infix::sanitize_tag(text.as_str());
+        if text.is_empty() {
+            return None;
+        }
+        tag.push_str(&template[at..start]);
+        tag.push_str(&text);
+        at = end + 1;
+    }
+    tag.push_str(&template[at..]);
+    Some(tag)
+}
+
+/// Print the tags added to the entry `name`.
+pub fn print_additions(name: &str, tags: &[String]) {
+    let added = tags.iter().map(|t| format!("+{}", t)).collect::<Vec<_>>();
+    println!("{}: {}", name, added.join(" "));
+}
+
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Load the rules file at `path`. There are no rules if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut text = String::new();
+        match fs::File::open(path) {
+            Ok(mut file) => {
+                file.read_to_string(&mut text)
+                    .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Couldn't read {}: {}", path.display(), e)),
+        }
+        Self::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = Self::parse_rule(line).map_err(|e| format!("Line {}: {}", i + 1, e))?;
+            rules.push(rule);
+        }
+        Ok(Rules { rules })
+    }
+    fn parse_rule(line: &str) -> Result<Rule, String> {
+        let arrow = match line.rfind("=>") {
+            Some(arrow) => arrow,
+            None => return Err("Expected `PATTERN => TAGS`".to_owned()),
+        };
+        let pattern = line[..arrow].trim();
+        let tags = line[arrow + 2..]
+            .split_whitespace()
+            .map(|t| t.to_owned())
+            .collect::<Vec<_>>();
+        if pattern.is_empty() || tags.is_empty() {
+            return Err("Expected `PATTERN => TAGS`".to_owned());
+        }
+        let pattern = match pattern.strip_prefix("glob:") {
+            Some(glob) => Pattern::Glob(glob.to_owned()),
+            None => Pattern::Regex(Regex::new(pattern).map_err(|e| e.to_string())?),
+        };
+        let group_names = match pattern {
+            Pattern::Glob(_) => Vec::new(),
+            Pattern::Regex(ref regex) => {
+                let mut names = regex
+                    .capture_names()
+                    .filter_map(|name| name.map(|n| n.to_owned()))
+                    .collect::<Vec<_>>();
+                names.extend((0..regex.captures_len()).map(|i| i.to_string()));
+                names
+            }
+        };
+        for tag in &tags {
+            for (start, end) in placeholders(tag)? {
+                let group = &tag[start + 1..end];
+                if !group_names.iter().any(|n| n == group) {
+                    return Err(format!("{} refers to an unknown group: {}", tag, group));
+                }
+            }
+        }
+        Ok(Rule { pattern, tags })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    /// The tags the rules give the entry `name`.
+    pub fn tags(&self, name: &str) -> Vec<String> {
+        let mut tags = Vec::new();
+        for rule in &self.rules {
+            let rule_tags = match rule.pattern {
+                Pattern::Glob(ref glob) => if config::pattern_matches(glob, name) {
+                    rule.tags.clone()
+                } else {
+                    Vec::new()
+                },
+                Pattern::Regex(ref regex) => match regex.captures(name) {
+                    Some(captures) => rule.tags
+                        .iter()
+                        .filter_map(|t| expand(t, &captures))
+                        .collect(),
+                    None => Vec::new(),
+                },
+            };
+            for tag in rule_tags {
+                if !tags.contains(&tag) {
+                    tags.push(tag);
+                }
+            }
+        }
+        tags
+    }
+    /// The tags the rules would add to the entries `names`, for the entries that get any.
+    pub fn preview(&self, list: &TaggerMap, names: &[String]) -> Vec<(String, Vec<String>)> {
+        let mut additions = Vec::new();
+        for name in names {
+            let tags = match list.tag_map.entries.get(name) {
+                Some(tags) => tags,
+                None => continue,
+            };
+            let new_tags = self.tags(name)
+                .into_iter()
+                .filter(|t| !tags.contains(t))
+                .collect::<Vec<_>>();
+            if !new_tags.is_empty() {
+                additions.push((name.clone(), new_tags));
+            }
+        }
+        additions
+    }
+    /// Add the tags the rules give to the entries `names`, and print them.
+    ///
+    /// Returns how many entries got new tags.
+    pub fn apply(&self, list: &mut TaggerMap, names: &[String]) -> usize {
+        let additions = self.preview(list, names);
+        for (name, new_tags) in &additions {
+            print_additions(name, new_tags);
+            let tags = list.tag_map.entries.get_mut(name).unwrap();
+            tags.extend(new_tags.iter().cloned());
+        }
+        additions.len()
+    }
+}
+
+#[test]
+fn test_rules() {
+    let rules = Rules::parse(
+        "# Dates
+         ^(?P<year>\\d{4})-(\\d{2})- => year:{year} month:{year}-{2}
+         _(?P<place>[a-z ]+)_ => place:{place}
+         glob:*.jpg => photo",
+    ).unwrap();
+    assert_eq!(
+        rules.tags("2017-05-03_beach house_IMG001.jpg"),
+        ["year:2017", "month:2017-05", "place:beach_house", "photo"]
+    );
+    assert!(rules.tags("notes.txt").is_empty());
+    let rules = Rules::parse("^(\\w+ & \\w+) => pair:{1}").unwrap();
+    assert_eq!(rules.tags("salt & pepper.jpg"), ["pair:salt_pepper"]);
+    assert!(Rules::parse("(?P<year>\\d{4}) => year:{yaer}").is_err());
+    assert!(Rules::parse("just a pattern").is_err());
+}
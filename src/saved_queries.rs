@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq)]
+pub enum ExpandError {
+    /// Reference to a saved query that doesn't exist
+    Unknown(String),
+    /// A saved query that refers back to itself, along with the chain of references
+    Recursive(Vec<String>),
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpandError::Unknown(ref name) => write!(f, "Unknown saved query: @{}", name),
+            ExpandError::Recursive(ref chain) => {
+                write!(f, "Recursive saved query: @{}", chain.join(" -> @"))
+            }
+        }
+    }
+}
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || "()!&|@".contains(c)
+}
+
+/// Whether `name` can be used as the name of a saved query.
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(is_special)
+}
+
+/// Replace every `@name` reference in an infix expression with the saved query it refers to.
+pub fn expand(text: &str, queries: &BTreeMap<String, String>) -> Result<String, ExpandError> {
+    expand_inner(text, queries, &mut Vec::new())
+}
+
+fn expand_inner(
+    text: &str,
+    queries: &BTreeMap<String, String>,
+    stack: &mut Vec<String>,
+) -> Result<String, ExpandError> {
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    // `@` only starts a reference at the beginning of a word, so tags like `foo@bar` are kept
+    let mut word_start = true;
+    while let Some(c) = chars.next() {
+        if c != '@' || !word_start {
+            word_start = is_special(c);
+            out.push(c);
+            continue;
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if is_special(c) {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if stack.contains(&name) {
+            stack.push(name);
+            return Err(ExpandError::Recursive(stack.clone()));
+        }
+        let query = match queries.get(&name) {
+            Some(query) => query,
+            None => return Err(ExpandError::Unknown(name)),
+        };
+        stack.push(name);
+        let expanded = expand_inner(query, queries, stack)?;
+        stack.pop();
+        out.push('(');
+        out.push_str(&expanded);
+        out.push(')');
+        word_start = false;
+    }
+    Ok(out)
+}
+
+#[test]
+fn test_expand() {
+    let mut queries = BTreeMap::new();
+    queries.insert("favs".to_owned(), "best !nsfw".to_owned());
+    queries.insert("cats".to_owned(), "cat @favs".to_owned());
+    assert_eq!(
+        expand("@cats foo@bar", &queries),
+        Ok("(cat (best !nsfw)) foo@bar".to_owned())
+    );
+    assert_eq!(
+        expand("@dogs", &queries),
+        Err(ExpandError::Unknown("dogs".to_owned()))
+    );
+}
+
+#[test]
+fn test_expand_recursive() {
+    let mut queries = BTreeMap::new();
+    queries.insert("a".to_owned(), "x @b".to_owned());
+    queries.insert("b".to_owned(), "!@a".to_owned());
+    assert_eq!(
+        expand("@a", &queries),
+        Err(ExpandError::Recursive(vec!["a".into(), "b".into(), "a".into()]))
+    );
+}
+
+#[test]
+fn test_expanded_query_matches() {
+    let mut queries = BTreeMap::new();
+    queries.insert("favs".to_owned(), "best & !nsfw".to_owned());
+    queries.insert("pets".to_owned(), "cat | dog".to_owned());
+    let matches = |query: &str, tags: &[&str]| {
+        let tags = tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let expanded = expand(query, &queries).unwrap();
+        ::infix::parse_infix(&expanded).unwrap().matches(&tags)
+    };
+    assert!(matches("!@favs", &["best", "nsfw"]));
+    assert!(matches("!@favs", &["cat"]));
+    assert!(!matches("!@favs", &["best"]));
+    assert!(matches("@pets | @favs", &["best"]));
+    assert!(matches("@pets !@favs", &["dog"]));
+    assert!(!matches("@pets @favs", &["dog", "nsfw", "best"]));
+}
@@ -0,0 +1,314 @@
+use completion::{complete_query_word, complete_word};
+use journal::{Change, Snapshot};
+use library::Library;
+use rustyline::{self, Context, Editor, Helper};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use tagger_map::TaggerMap;
+
+const HELP: &str = "\
+filt [QUERY]          List entries matching QUERY
+tag FILE TAGS...      Add tags to an entry
+untag FILE TAGS...    Remove tags from an entry
+mv SRC DST            Rename a file along with its entry
+stats                 Show statistics about the list
+save                  Save the list
+undo                  Undo the last change
+quit                  Leave the shell";
+
+const COMMANDS: &[&str] = &[
+    "filt", "tag", "untag", "mv", "stats", "save", "undo", "help", "quit",
+];
+
+/// Completes command names, then file names or tags depending on the command.
+struct ShellCompleter {
+    tags: RefCell<BTreeSet<String>>,
+    files: RefCell<BTreeSet<String>>,
+}
+
+impl Completer for ShellCompleter {
+    type Candidate = String;
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let begin = line[..pos].rfind(' ').map_or(0, |p| p + 1);
+        let arg_index = line[..begin].split_whitespace().count();
+        if arg_index == 0 {
+            let commands = COMMANDS.iter().map(|&c| c.to_owned()).collect::<Vec<_>>();
+            return Ok(complete_word(line, pos, &commands));
+        }
+        Ok(match line.split_whitespace().next().unwrap_or("") {
+            "tag" | "untag" if arg_index == 1 => complete_word(line, pos, &*self.files.borrow()),
+            "mv" => complete_word(line, pos, &*self.files.borrow()),
+            "filt" => complete_query_word(line, pos, &*self.tags.borrow()),
+            "tag" | "untag" => complete_word(line, pos, &*self.tags.borrow()),
+            _ => (begin, Vec::new()),
+        })
+    }
+}
+
+impl Hinter for ShellCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for ShellCompleter {}
+
+impl Validator for ShellCompleter {}
+
+impl Helper for ShellCompleter {}
+
+struct Shell<'a> {
+    list: &'a mut TaggerMap,
+    library: &'a Library,
+    completer: &'a ShellCompleter,
+    undo_stack: Vec<Change>,
+    /// Whether there are changes that weren't saved yet
+    dirty: bool,
+    /// The list as it was last saved, for recording the changes in the journal
+    saved: Snapshot,
+    /// Files renamed since the list was last saved
+    renames: Vec<Change>,
+}
+
+impl<'a> Shell<'a> {
+    fn exec(&mut self, command: &str, args: &[String]) -> Result<(), String> {
+        match command {
+            "filt" => self.filt(&args.join(" ")),
+            "tag" | "untag" => {
+                if args.len() < 2 {
+                    return Err(format!("Usage: {} FILE TAGS...", command));
+                }
+                let change = if command == "tag" {
+                    Change::Tag(args[0].clone(), args[1..].to_vec())
+                } else {
+                    Change::Untag(args[0].clone(), args[1..].to_vec())
+                };
+                self.apply(change, true)
+            }
+            "mv" => {
+                if args.len() != 2 {
+                    return Err("Usage: mv SRC DST".into());
+                }
+                self.apply(Change::Mv(args[0].clone(), args[1].clone()), true)
+            }
+            "stats" => {
+                self.stats();
+                Ok(())
+            }
+            "save" => {
+                ::save_list(self.library, self.list, &self.saved, "shell", self.renames.clone())?;
+                self.saved = Snapshot::of(self.list);
+                self.renames.clear();
+                self.dirty = false;
+                Ok(())
+            }
+            "undo" => match self.undo_stack.pop() {
+                Some(change) => self.apply(change.inverse(), false),
+                None => Err("Nothing to undo.".into()),
+            },
+            "help" => {
+                println!("{}", HELP);
+                Ok(())
+            }
+            _ => Err(format!("Unknown command: {}. Type `help` for a list.", command)),
+        }
+    }
+    fn filt(&self, query: &str) -> Result<(), String> {
+        for name in self.list.select(query, false)? {
+            println!("{}", name);
+        }
+        Ok(())
+    }
+    /// Apply a change, recording only the part that actually changed something for undo.
+    fn apply(&mut self, change: Change, record: bool) -> Result<(), String> {
+        let change = match change {
+            Change::Tag(name, tags) => {
+                let mut added = Vec::new();
+                for tag in tags {
+                    if !self.entry(&name)?.contains(&tag) && !added.contains(&tag) {
+                        added.push(tag);
+                    }
+                }
+                Change::Tag(name, added)
+            }
+            Change::Untag(name, tags) => {
+                let mut removed = Vec::new();
+                for tag in tags {
+                    if self.entry(&name)?.contains(&tag) && !removed.contains(&tag) {
+                        removed.push(tag);
+                    }
+                }
+                Change::Untag(name, removed)
+            }
+            change => change,
+        };
+        change.apply(self.list)?;
+        match change {
+            Change::Tag(_, ref tags) => {
+                self.completer.tags.borrow_mut().extend(tags.iter().cloned());
+            }
+            Change::Mv(ref src, ref dst) => {
+                let mut files = self.completer.files.borrow_mut();
+                files.remove(src);
+                files.insert(dst.clone());
+                self.renames.push(change.clone());
+            }
+            _ => {}
+        }
+        self.dirty = true;
+        if record {
+            self.undo_stack.push(change);
+        }
+        Ok(())
+    }
+    /// Drop the unsaved changes. Files renamed since the last save are moved back first, so they
+    /// match the saved list again, and if that fails the changes are saved instead.
+    fn discard(&mut self) -> Result<(), String> {
+        while let Some(change) = self.renames.pop() {
+            if let Err(e) = change.inverse().apply(self.list) {
+                self.renames.push(change);
+                eprintln!("Error: {}", e);
+                eprintln!("Couldn't move all renamed files back, saving the changes instead.");
+                return self.exec("save", &[]);
+            }
+        }
+        Ok(())
+    }
+    fn entry(&self, name: &str) -> Result<&Vec<String>, String> {
+        self.list
+            .tag_map
+            .entries
+            .get(name)
+            .ok_or_else(|| format!("No entry named {}", name))
+    }
+    fn stats(&self) {
+        let entries = &self.list.tag_map.entries;
+        let untagged = entries.values().filter(|tags| tags.is_empty()).count();
+        let mut counts = BTreeMap::new();
+        for tags in entries.values() {
+            for tag in tags {
+                *counts.entry(tag).or_insert(0) += 1;
+            }
+        }
+        println!("Entries: {} ({} untagged)", entries.len(), untagged);
+        println!("Tags: {}", counts.len());
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by_key(|&(_, count)| ::std::cmp::Reverse(count));
+        for (tag, count) in counts.into_iter().take(10) {
+            println!("{:>8} {}", count, tag);
+        }
+    }
+}
+
+/// Split a command line into whitespace separated arguments, allowing "quoted arguments".
+pub fn split_args(line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            _ if c.is_whitespace() && !in_quotes => if has_arg {
+                args.push(arg.clone());
+                arg.clear();
+                has_arg = false;
+            },
+            _ => {
+                arg.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(arg);
+    }
+    args
+}
+
+pub fn run(list: &mut TaggerMap, library: &Library) {
+    let completer = ShellCompleter {
+        tags: RefCell::new(list.tags()),
+        files: RefCell::new(list.tag_map.entries.keys().cloned().collect()),
+    };
+    let mut editor = Editor::new();
+    editor.set_helper(Some(&completer));
+    let history_path = library.own_file(::SHELL_HISTORY_FILENAME);
+    // There is no history yet the first time the shell is used
+    let _ = editor.load_history(&history_path);
+    let mut shell = Shell {
+        saved: Snapshot::of(list),
+        list,
+        library,
+        completer: &completer,
+        undo_stack: Vec::new(),
+        dirty: false,
+        renames: Vec::new(),
+    };
+    loop {
+        let line = match editor.readline("tagger> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => "quit".to_owned(),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                "quit".to_owned()
+            }
+        };
+        editor.add_history_entry(&line);
+        let args = split_args(&line);
+        match args.first().map(|s| &s[..]) {
+            None => {}
+            Some("quit") | Some("exit") => {
+                if !shell.dirty {
+                    break;
+                }
+                match editor.readline("There are unsaved changes. Save them? [y/n/c] ") {
+                    Ok(ref answer) if answer.starts_with('y') => {
+                        match shell.exec("save", &[]) {
+                            Ok(()) => break,
+                            Err(e) => eprintln!("Error: {}", e),
+                        }
+                    }
+                    Ok(ref answer) if answer.starts_with('n') => match shell.discard() {
+                        Ok(()) => break,
+                        Err(e) => eprintln!("Error: {}", e),
+                    },
+                    Err(ReadlineError::Eof) => {
+                        eprintln!("No answer, discarding unsaved changes.");
+                        if let Err(e) = shell.discard() {
+                            eprintln!("Error: {}", e);
+                        }
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            Some(command) => if let Err(e) = shell.exec(command, &args[1..]) {
+                eprintln!("Error: {}", e);
+            },
+        }
+    }
+    if let Err(e) = editor.save_history(&history_path) {
+        eprintln!("Error saving {}: {}", history_path.display(), e);
+    }
+}
+
+#[test]
+fn test_split_args() {
+    assert_eq!(
+        split_args(r#"mv "my file.png"  b.png """#),
+        vec!["mv", "my file.png", "b.png", ""]
+    );
+}
@@ -1,12 +1,19 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::io::{self, BufReader, BufWriter};
 use std::io::prelude::*;
 use std::path::Path;
+use config;
+use infix::{self, Expr};
 use tagmap::TagMap;
+use saved_queries::{self, ExpandError};
+use fuzzy;
+use library;
 
 pub struct TaggerMap {
     pub tag_map: TagMap<String, String>,
+    /// Saved queries, by name
+    pub queries: BTreeMap<String, String>,
 }
 
 impl Default for TaggerMap {
@@ -19,13 +26,23 @@ impl TaggerMap {
     pub fn new() -> Self {
         TaggerMap {
             tag_map: TagMap::new(),
+            queries: BTreeMap::new(),
         }
     }
     pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         let reader = BufReader::new(fs::File::open(path)?);
         let mut map = TagMap::new();
+        let mut queries = BTreeMap::new();
         for line in reader.lines() {
             let line = line?;
+            // Saved queries are stored as `@name query`
+            if let Some(rest) = line.strip_prefix('@') {
+                let mut parts = rest.splitn(2, ' ');
+                let name = parts.next().unwrap_or("");
+                let query = parts.next().unwrap_or("");
+                queries.insert(name.to_owned(), query.to_owned());
+                continue;
+            }
             let quot1 = line.find('"').unwrap();
             let quot2 = line[quot1 + 1..].find('"').unwrap();
             let filename = &line[quot1 + 1..quot2 + 1];
@@ -35,24 +52,42 @@ impl TaggerMap {
                 .collect::<Vec<_>>();
             map.entries.insert(filename.into(), tags);
         }
-        Ok(TaggerMap { tag_map: map })
+        Ok(TaggerMap {
+            tag_map: map,
+            queries,
+        })
     }
 
     /// Add entries in a directory that aren't present in the List yet.
     ///
-    /// Returns how much entries were added.
-    pub fn update_from_dir<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+    /// `list_file` is the name of the list itself, which is never added, and neither are the
+    /// list's own files.
+    /// Neither are files matching one of the `ignore` patterns.
+    ///
+    /// Returns the names of the entries that were added.
+    pub fn update_from_dir<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        path: P,
+        list_file: Q,
+        ignore: &[&str],
+    ) -> io::Result<Vec<String>> {
         use std::collections::btree_map::Entry;
-        let mut added_count = 0;
+        let mut added = Vec::new();
         // Check for files that aren't part of the list and add them
         for entry in fs::read_dir(path)? {
             let entry = entry?;
             let name = entry.file_name().into_string().unwrap();
-            if name != ::LIST_DEFAULT_FILENAME {
+            let ignored = ignore.iter().any(|p| config::pattern_matches(p, &name));
+            let is_own_file = ::TAGGER_FILENAMES
+                .iter()
+                .any(|n| library::own_file_name(list_file.as_ref(), n) == Path::new(&name));
+            if !::TAGGER_FILENAMES.contains(&&name[..]) && Path::new(&name) != list_file.as_ref()
+                && !is_own_file && !ignored
+            {
                 if let Entry::Vacant(entry) = self.tag_map.entries.entry(name.clone()) {
                     println!("Adding {}", name);
                     entry.insert(Vec::new());
-                    added_count += 1;
+                    added.push(name);
                 }
             }
         }
@@ -67,7 +102,7 @@ impl TaggerMap {
             println!("Removing {}", k);
             self.tag_map.entries.remove(&k);
         }
-        Ok(added_count)
+        Ok(added)
     }
 
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
@@ -79,6 +114,9 @@ impl TaggerMap {
             }
             write!(writer, "\n")?;
         }
+        for (name, query) in &self.queries {
+            writeln!(writer, "@{} {}", name, query)?;
+        }
         Ok(())
     }
 
@@ -94,4 +132,95 @@ impl TaggerMap {
 
         set
     }
+
+    /// Expands references to saved queries in an infix expression.
+    pub fn expand_query(&self, text: &str) -> Result<String, ExpandError> {
+        saved_queries::expand(text, &self.queries)
+    }
+
+    /// Parse an infix expression that may refer to saved queries.
+    pub fn parse_query(&self, text: &str) -> Result<Expr, String> {
+        let expr = self.expand_query(text).map_err(|e| e.to_string())?;
+        infix::parse_infix(&expr).map_err(|e| format!("Invalid infix expression: {}", e))
+    }
+
+    /// Names of the entries matching an infix expression.
+    ///
+    /// Unknown tags in it are reported, or are an error if `strict` is set.
+    pub fn select(&self, query: &str, strict: bool) -> Result<Vec<String>, String> {
+        let selected = select_in(&[self], query, strict)?;
+        Ok(selected.into_iter().map(|(_, name, _)| name.clone()).collect())
+    }
+
+    /// The entries matching a parsed expression, along with their tags.
+    pub fn matching<'a>(&'a self, expr: &Expr) -> Vec<(&'a String, &'a Vec<String>)> {
+        self.tag_map
+            .entries
+            .iter()
+            .filter(|&(_, tags)| expr.matches(tags))
+            .collect()
+    }
+}
+
+/// An entry of one of several lists: the index of its list, its name and its tags
+pub type ListEntry<'a> = (usize, &'a String, &'a Vec<String>);
+
+/// The entries of `lists` matching an infix expression.
+///
+/// Unknown tags in it are reported, or are an error if `strict` is set.
+pub fn select_in<'a>(
+    lists: &[&'a TaggerMap],
+    query: &str,
+    strict: bool,
+) -> Result<Vec<ListEntry<'a>>, String> {
+    let mut selected = Vec::new();
+    let mut known = BTreeSet::new();
+    let mut query_tags = BTreeSet::new();
+    for (i, list) in lists.iter().enumerate() {
+        // Saved queries can differ between the lists
+        let expr = list.parse_query(query)?;
+        for (name, tags) in list.matching(&expr) {
+            selected.push((i, name, tags));
+        }
+        known.extend(list.tags());
+        query_tags.extend(expr.tags().into_iter().map(|(tag, negated)| (tag.clone(), negated)));
+    }
+    check_tags(&query_tags, &known, strict)?;
+    Ok(selected)
+}
+
+/// Report the `query_tags` that aren't `known`, along with similar tags.
+///
+/// If `strict` is set, they're an error instead, unless they're negated. Excluding a tag that
+/// no entry has doesn't change what's selected.
+fn check_tags(
+    query_tags: &BTreeSet<(String, bool)>,
+    known: &BTreeSet<String>,
+    strict: bool,
+) -> Result<(), String> {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    for &(ref tag, negated) in query_tags {
+        if known.contains(tag) {
+            continue;
+        }
+        let similar = fuzzy::similar_tags(tag, known);
+        let problem = if similar.is_empty() {
+            format!("No entry is tagged {}", tag)
+        } else {
+            format!("No entry is tagged {}. Did you mean {}?", tag, similar.join(", "))
+        };
+        if strict && !negated {
+            errors.push(problem);
+        } else {
+            warnings.push(problem);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    Ok(())
 }
@@ -0,0 +1,88 @@
+//! File name templates for renaming entries based on their tags.
+//!
+//! Placeholders:
+//!
+//! - `{tag:NAMESPACE}`: The value of the entry's `NAMESPACE:value` tag
+//! - `{index}`: The position of the entry among the renamed entries, starting from 1.
+//!   `{index:03}` pads it with zeroes to 3 digits.
+//! - `{name}`: The file name without extension
+//! - `{ext}`: The extension of the file
+//!
+//! `{{` and `}}` stand for literal braces.
+
+use std::path::Path;
+
+/// Render `template` for the entry `name` with `tags`.
+pub fn render(template: &str, name: &str, tags: &[String], index: usize) -> Result<String, String> {
+    let path = Path::new(name);
+    let mut out = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => field.push(c),
+                        None => return Err(format!("Unclosed placeholder in {}", template)),
+                    }
+                }
+                out.push_str(&field_value(&field, path, tags, index)?);
+            }
+            '}' => return Err(format!("Unmatched }} in {}", template)),
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+fn field_value(field: &str, path: &Path, tags: &[String], index: usize) -> Result<String, String> {
+    let mut parts = field.splitn(2, ':');
+    let (kind, arg) = (parts.next().unwrap_or(""), parts.next());
+    let value = match (kind, arg) {
+        ("tag", Some(namespace)) => {
+            let prefix = format!("{}:", namespace);
+            match tags.iter().find(|t| t.starts_with(&prefix)) {
+                Some(tag) => tag[prefix.len()..].to_owned(),
+                None => return Err(format!("{} has no {} tag", path.display(), prefix)),
+            }
+        }
+        ("index", None) => index.to_string(),
+        ("index", Some(width)) => match width.parse::<usize>() {
+            Ok(width) => format!("{:0width$}", index, width = width),
+            Err(_) => return Err(format!("Invalid index width: {}", width)),
+        },
+        ("name", None) => path.file_stem()
+            .map_or(String::new(), |s| s.to_string_lossy().into_owned()),
+        ("ext", None) => path.extension()
+            .map_or(String::new(), |s| s.to_string_lossy().into_owned()),
+        _ => return Err(format!("Unknown placeholder: {{{}}}", field)),
+    };
+    if value.contains('/') {
+        return Err(format!("{{{}}} of {} contains a /", field, path.display()));
+    }
+    Ok(value)
+}
+
+#[test]
+fn test_render() {
+    let tags = vec!["comic".to_owned(), "author:jim".to_owned()];
+    assert_eq!(
+        render("{tag:author}_{index:03}.{ext}", "dir/x.png", &tags, 7),
+        Ok("jim_007.png".to_owned())
+    );
+    assert_eq!(
+        render("{{{name}}}", "x.png", &tags, 1),
+        Ok("{x}".to_owned())
+    );
+    assert!(render("{tag:title}", "x.png", &tags, 1).is_err());
+}
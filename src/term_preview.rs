@@ -0,0 +1,231 @@
+//! Showing images right in the terminal, for when there's no graphical tool to show them with.
+//!
+//! Supports the kitty graphics protocol and sixels, falling back to coloured Unicode half blocks
+//! that any terminal with true colour support can show.
+
+use image::{self, FilterType, RgbaImage};
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+/// How images are drawn
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Kitty,
+    Sixel,
+    Blocks,
+}
+
+impl Protocol {
+    /// The protocol from the `add-tags.preview` setting, or the best one the terminal seems
+    /// to support.
+    pub fn select(setting: Option<&str>) -> Self {
+        match setting {
+            Some("kitty") => return Protocol::Kitty,
+            Some("sixel") => return Protocol::Sixel,
+            Some("blocks") => return Protocol::Blocks,
+            _ => {}
+        }
+        let var = |name| env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let term_program = var("TERM_PROGRAM");
+        if env::var_os("KITTY_WINDOW_ID").is_some() || term.contains("kitty")
+            || term_program == "WezTerm"
+        {
+            Protocol::Kitty
+        } else if term.contains("sixel") || term.starts_with("mlterm") || term.starts_with("foot")
+            || term.starts_with("yaft")
+        {
+            Protocol::Sixel
+        } else {
+            Protocol::Blocks
+        }
+    }
+}
+
+/// Size of the terminal in columns and rows.
+fn terminal_size() -> (u32, u32) {
+    let var = |name| env::var(name).ok().and_then(|v| v.parse().ok());
+    if let (Some(cols), Some(rows)) = (var("COLUMNS"), var("LINES")) {
+        return (cols, rows);
+    }
+    // `stty` reports the size of the terminal it gets as stdin
+    let output = Command::new("stty")
+        .arg("size")
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output();
+    if let Ok(output) = output {
+        let text = String::from_utf8_lossy(&output.stdout);
+        let mut numbers = text.split_whitespace().filter_map(|n| n.parse().ok());
+        if let (Some(rows), Some(cols)) = (numbers.next(), numbers.next()) {
+            return (cols, rows);
+        }
+    }
+    (80, 24)
+}
+
+/// Clear the terminal and draw the image at `path` on it, leaving some room for the prompt.
+pub fn show(path: &str, protocol: Protocol) -> Result<(), String> {
+    let image = image::open(path).map_err(|e| e.to_string())?;
+    let (cols, rows) = terminal_size();
+    let rows = rows.saturating_sub(3).max(1);
+    let out = match protocol {
+        // Assume cells of 10x20 pixels
+        Protocol::Kitty | Protocol::Sixel => {
+            let image = image.resize(cols * 10, rows * 20, FilterType::Triangle).to_rgba();
+            if protocol == Protocol::Kitty {
+                kitty(&image, cols, rows)
+            } else {
+                sixel(&image)
+            }
+        }
+        // Each character shows two pixels on top of each other, which are about square
+        Protocol::Blocks => blocks(&image.resize(cols, rows * 2, FilterType::Triangle).to_rgba()),
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let result = if protocol == Protocol::Kitty {
+        // Delete the previous image, then clear the text
+        writeln!(stdout, "\x1b_Ga=d\x1b\\\x1b[2J\x1b[H{}", out)
+    } else {
+        writeln!(stdout, "\x1b[2J\x1b[H{}", out)
+    };
+    result.and_then(|_| stdout.flush()).map_err(|e| e.to_string())
+}
+
+/// The colour of a pixel put on a black background.
+fn rgb(image: &RgbaImage, x: u32, y: u32) -> [u8; 3] {
+    let p = image.get_pixel(x, y).data;
+    let blend = |c: u8| (u32::from(c) * u32::from(p[3]) / 255) as u8;
+    [blend(p[0]), blend(p[1]), blend(p[2])]
+}
+
+fn blocks(image: &RgbaImage) -> String {
+    let (w, h) = image.dimensions();
+    let mut out = String::new();
+    for y in (0..h).filter(|y| y % 2 == 0) {
+        for x in 0..w {
+            let top = rgb(image, x, y);
+            out.push_str(&format!("\x1b[38;2;{};{};{}m", top[0], top[1], top[2]));
+            if y + 1 < h {
+                let bottom = rgb(image, x, y + 1);
+                out.push_str(&format!("\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2]));
+            }
+            out.push('\u{2580}');
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(CHARS[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Kitty graphics protocol escape codes that show `image` in at most `cols` x `rows` cells.
+fn kitty(image: &RgbaImage, cols: u32, rows: u32) -> String {
+    let (w, h) = image.dimensions();
+    // Fit the image in the cells, which are about twice as high as they're wide
+    let (c, r) = if w * rows * 2 > h * cols {
+        (cols, (h * cols / (w * 2)).max(1))
+    } else {
+        ((w * rows * 2 / h).max(1), rows)
+    };
+    let data = base64(image);
+    let mut out = String::new();
+    // The data has to be sent in chunks of at most 4096 bytes
+    let chunks = data.as_bytes().chunks(4096).collect::<Vec<_>>();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let chunk = String::from_utf8_lossy(chunk);
+        if i == 0 {
+            out.push_str(&format!(
+                "\x1b_Ga=T,f=32,s={},v={},c={},r={},m={};{}\x1b\\",
+                w, h, c, r, more, chunk
+            ));
+        } else {
+            out.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    out
+}
+
+/// Index of the closest colour in a 6x6x6 colour cube.
+fn palette_index(c: [u8; 3]) -> usize {
+    let level = |v: u8| (usize::from(v) * 5 + 127) / 255;
+    level(c[0]) * 36 + level(c[1]) * 6 + level(c[2])
+}
+
+/// Append a sixel character `count` times, run length encoded.
+fn push_run(out: &mut String, c: char, count: usize) {
+    match count {
+        0 => {}
+        1..=3 => out.extend((0..count).map(|_| c)),
+        _ => out.push_str(&format!("!{}{}", count, c)),
+    }
+}
+
+/// Sixel escape codes that show `image`, with its colours reduced to a 6x6x6 colour cube.
+fn sixel(image: &RgbaImage) -> String {
+    let (w, h) = image.dimensions();
+    let mut out = format!("\x1bPq\"1;1;{};{}", w, h);
+    for i in 0..216 {
+        let (r, g, b) = (i / 36, i / 6 % 6, i % 6);
+        out.push_str(&format!("#{};2;{};{};{}", i, r * 20, g * 20, b * 20));
+    }
+    let indices = (0..h)
+        .map(|y| (0..w).map(|x| palette_index(rgb(image, x, y))).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    // Every sixel is a column of 6 pixels, drawn once for each colour in it
+    for band in indices.chunks(6) {
+        let mut colors = band.iter().flat_map(|row| row.iter().cloned()).collect::<Vec<_>>();
+        colors.sort();
+        colors.dedup();
+        for (n, &color) in colors.iter().enumerate() {
+            if n > 0 {
+                out.push('$');
+            }
+            out.push_str(&format!("#{}", color));
+            let mut run = (' ', 0);
+            for x in 0..w as usize {
+                let bits = band
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, row)| row[x] == color)
+                    .fold(0, |bits, (i, _)| bits | 1 << i);
+                let c = (63 + bits) as u8 as char;
+                if c == run.0 {
+                    run.1 += 1;
+                } else {
+                    push_run(&mut out, run.0, run.1);
+                    run = (c, 1);
+                }
+            }
+            push_run(&mut out, run.0, run.1);
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\");
+    out
+}
+
+#[test]
+fn test_base64() {
+    assert_eq!(base64(b"tagger"), "dGFnZ2Vy");
+    assert_eq!(base64(b"tags"), "dGFncw==");
+    assert_eq!(base64(b"tag:"), "dGFnOg==");
+}
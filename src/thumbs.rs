@@ -0,0 +1,171 @@
+//! Thumbnails of entries, cached in a directory of the library.
+//!
+//! Cached thumbnails are keyed by the name, modification time and size of the file, along with
+//! the size of the thumbnail, so changed files get new thumbnails.
+
+use image::{self, FilterType, ImageError, RgbaImage};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Why there's no thumbnail of an entry
+#[derive(Debug)]
+pub enum Failure {
+    Missing,
+    PermissionDenied,
+    UnsupportedFormat,
+    Corrupt(String),
+    Other(String),
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::NotFound => Failure::Missing,
+            io::ErrorKind::PermissionDenied => Failure::PermissionDenied,
+            _ => Failure::Other(error.to_string()),
+        }
+    }
+}
+
+impl From<ImageError> for Failure {
+    fn from(error: ImageError) -> Self {
+        match error {
+            ImageError::IoError(error) => error.into(),
+            ImageError::UnsupportedError(_) | ImageError::UnsupportedColor(_) => {
+                Failure::UnsupportedFormat
+            }
+            ImageError::FormatError(_)
+            | ImageError::DimensionError
+            | ImageError::NotEnoughData
+            | ImageError::ImageEnd => Failure::Corrupt(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Missing => write!(f, "File is missing"),
+            Failure::PermissionDenied => write!(f, "Permission denied"),
+            Failure::UnsupportedFormat => write!(f, "Unsupported format"),
+            Failure::Corrupt(ref reason) => write!(f, "Corrupt image: {}", reason),
+            Failure::Other(ref reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// 64 bit FNV-1a hash, which unlike the hasher of the standard library stays the same across
+/// Rust versions.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Make a thumbnail of the image at `path` that fits in `size` x `size` pixels.
+pub fn generate(path: &str, size: u32) -> Result<RgbaImage, Failure> {
+    let mut buf = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut buf)?;
+    // Decoders can panic on broken files
+    let image = panic::catch_unwind(|| image::load_from_memory(&buf));
+    // Loading images is memory intensive, so the file contents are dropped as soon as possible
+    drop(buf);
+    match image {
+        Ok(image) => Ok(image?.resize(size, size, FilterType::Triangle).to_rgba()),
+        Err(_) => Err(Failure::Corrupt("The decoder crashed".to_owned())),
+    }
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// The thumbnail cache of the library at `root`.
+    pub fn new(root: &Path) -> Self {
+        Cache {
+            dir: root.join(::THUMBNAILS_DIRNAME),
+        }
+    }
+    /// Where the thumbnail of the entry `name` of `size` pixels is kept.
+    fn path(&self, name: &str, size: u32) -> io::Result<PathBuf> {
+        let metadata = fs::metadata(name)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let key = format!(
+            "{}\0{}.{}\0{}\0{}",
+            name,
+            modified.as_secs(),
+            modified.subsec_nanos(),
+            metadata.len(),
+            size
+        );
+        Ok(self.dir.join(format!("{:016x}.png", fnv1a(key.as_bytes()))))
+    }
+    /// The cached thumbnail of the entry `name`, if there is one.
+    fn load(&self, name: &str, size: u32) -> Option<RgbaImage> {
+        let path = self.path(name, size).ok()?;
+        image::open(path).ok().map(|image| image.to_rgba())
+    }
+    fn store(&self, name: &str, size: u32, thumbnail: &RgbaImage) -> io::Result<()> {
+        let path = self.path(name, size)?;
+        fs::create_dir_all(&self.dir)?;
+        // Write to another file first, so there's never half of a thumbnail in the cache
+        let temp_path = path.with_extension("tmp.png");
+        thumbnail.save(&temp_path)?;
+        fs::rename(temp_path, path)
+    }
+    /// Whether there's a thumbnail of the entry `name` in the cache.
+    pub fn contains(&self, name: &str, size: u32) -> bool {
+        self.path(name, size).is_ok_and(|path| path.exists())
+    }
+    /// The thumbnail of the entry `name`, from the cache if it's there, or generated and put
+    /// into the cache if it isn't.
+    pub fn thumbnail(&self, name: &str, size: u32) -> Result<RgbaImage, Failure> {
+        if let Some(thumbnail) = self.load(name, size) {
+            return Ok(thumbnail);
+        }
+        let thumbnail = generate(name, size)?;
+        if let Err(e) = self.store(name, size, &thumbnail) {
+            eprintln!("Couldn't cache the thumbnail of {}: {}", name, e);
+        }
+        Ok(thumbnail)
+    }
+    /// Remove the thumbnails that aren't of one of the entries `names` at `size`.
+    ///
+    /// Returns how many were removed.
+    pub fn clean(&self, names: &[String], size: u32) -> io::Result<usize> {
+        let keep = names
+            .iter()
+            .filter_map(|name| self.path(name, size).ok())
+            .collect::<BTreeSet<_>>();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if !keep.contains(&path) {
+                fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[test]
+fn test_fnv1a() {
+    assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+}
@@ -0,0 +1,150 @@
+//! Moving files to the trash, following the freedesktop.org trash specification.
+
+use fileops;
+use journal;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The home trash directory, `$XDG_DATA_HOME/Trash`.
+fn trash_dir() -> io::Result<PathBuf> {
+    let data_home = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".local/share"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Couldn't find the trash directory, HOME is not set",
+                ))
+            }
+        },
+    };
+    Ok(data_home.join("Trash"))
+}
+
+/// Percent-encode a path for the `Path` key of a trash info file.
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &b in path.to_string_lossy().as_bytes() {
+        if b < 0x80 && ((b as char).is_alphanumeric() || b"/-_.~".contains(&b)) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// The current local date and time, like `2017-10-18T21:01:01`.
+fn local_time() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    journal::format_time((secs as i64 + utc_offset(secs)) as u64).replace(' ', "T")
+}
+
+/// Seconds the local time zone is ahead of UTC at `secs` since the Unix epoch.
+#[cfg(unix)]
+fn utc_offset(secs: u64) -> i64 {
+    let time = secs as ::libc::time_t;
+    unsafe {
+        let mut tm: ::libc::tm = mem::zeroed();
+        if ::libc::localtime_r(&time, &mut tm).is_null() {
+            0
+        } else {
+            // A C long, which is only 32 bits on some platforms
+            tm.tm_gmtoff as i64
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn utc_offset(_secs: u64) -> i64 {
+    0
+}
+
+/// Write the info file of a trashed file that was at `path`.
+fn write_info(info: &mut fs::File, path: &Path) -> io::Result<()> {
+    let abs_path = env::current_dir()?.join(path);
+    write!(
+        info,
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        encode_path(&abs_path),
+        local_time()
+    )
+}
+
+/// The info file of `path` if it's a file in the trash.
+fn info_path(path: &Path) -> Option<PathBuf> {
+    let trash_dir = trash_dir().ok()?;
+    if path.parent()? != trash_dir.join("files") {
+        return None;
+    }
+    let name = path.file_name()?.to_string_lossy();
+    Some(trash_dir.join("info").join(format!("{}.trashinfo", name)))
+}
+
+/// Keep the info files of the trash in sync after `src` was moved to `dst`, such as when
+/// trashing a file is undone or redone.
+pub fn moved(src: &Path, dst: &Path) -> io::Result<()> {
+    if let Some(info_path) = info_path(src) {
+        match fs::remove_file(info_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    if let Some(info_path) = info_path(dst) {
+        write_info(&mut fs::File::create(info_path)?, src)?;
+    }
+    Ok(())
+}
+
+/// Move a file to the trash. Returns where the file ended up.
+pub fn trash(path: &Path) -> io::Result<PathBuf> {
+    let trash_dir = trash_dir()?;
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    fs::create_dir_all(&files_dir)?;
+    fs::create_dir_all(&info_dir)?;
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Path has no file name"))?
+        .to_string_lossy()
+        .into_owned();
+    // Creating the info file claims the name in the trash, so look for one that's free
+    let mut n = 1;
+    let (name, mut info) = loop {
+        let name = if n == 1 {
+            file_name.clone()
+        } else {
+            format!("{}.{}", file_name, n)
+        };
+        let info_path = info_dir.join(format!("{}.trashinfo", name));
+        match OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(file) => break (name, file),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e),
+        }
+    };
+    write_info(&mut info, path)?;
+    let trashed = files_dir.join(&name);
+    if let Err(e) = fileops::move_file(path, &trashed) {
+        drop(info);
+        let _ = fs::remove_file(info_dir.join(format!("{}.trashinfo", name)));
+        return Err(e);
+    }
+    Ok(trashed)
+}
+
+#[test]
+fn test_encode_path() {
+    assert_eq!(
+        encode_path(Path::new("/home/me/my file#1.png")),
+        "/home/me/my%20file%231.png"
+    );
+}
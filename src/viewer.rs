@@ -0,0 +1,138 @@
+//! Opening entries in external viewers.
+//!
+//! Viewers are command templates, like `mpv --loop {files}`. Placeholders:
+//!
+//! - `{files}`: All the files, as separate arguments
+//! - `{file}`: A single file. The viewer is launched once for each file.
+//! - `{dir}`: The directory of the file, or of the first file
+//!
+//! If there's no placeholder, the files are appended to the command.
+
+use config::Config;
+use shell::split_args;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+
+/// Used when no viewer is configured, or when the configured one can't be launched
+const FALLBACK_VIEWER: &str = "xdg-open {file}";
+
+/// The viewer template for `file`, from the `viewer.EXT` or `viewer` setting.
+fn template_for<'a>(config: &'a Config, file: &str) -> &'a str {
+    let ext = Path::new(file)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    ext.and_then(|ext| config.get(&format!("viewer.{}", ext)))
+        .or_else(|| config.get("viewer"))
+        .unwrap_or(FALLBACK_VIEWER)
+}
+
+fn dir_of(file: &str) -> String {
+    match Path::new(file).parent() {
+        Some(dir) if dir != Path::new("") => dir.to_string_lossy().into_owned(),
+        _ => ".".to_owned(),
+    }
+}
+
+/// Whether the viewer `template` is launched once for each file.
+fn opens_each_file(template: &str) -> bool {
+    split_args(template).iter().any(|w| w.contains("{file}"))
+}
+
+/// The command lines that open `files` with the viewer `template`.
+fn command_lines(template: &str, files: &[&str]) -> Result<Vec<Vec<String>>, String> {
+    let words = split_args(template);
+    if words.is_empty() {
+        return Err(format!("Empty viewer command: {}", template));
+    }
+    if opens_each_file(template) {
+        let lines = files
+            .iter()
+            .map(|file| {
+                words
+                    .iter()
+                    .map(|w| w.replace("{file}", file).replace("{dir}", &dir_of(file)))
+                    .collect()
+            })
+            .collect();
+        return Ok(lines);
+    }
+    let dir = files.first().map_or(".".to_owned(), |file| dir_of(file));
+    let mut line = Vec::new();
+    let mut has_files = false;
+    for word in &words {
+        if word == "{files}" {
+            line.extend(files.iter().map(|f| f.to_string()));
+            has_files = true;
+        } else {
+            line.push(word.replace("{dir}", &dir));
+        }
+    }
+    if !has_files {
+        line.extend(files.iter().map(|f| f.to_string()));
+    }
+    Ok(vec![line])
+}
+
+/// Launch the viewer `template` for `files`, without waiting for it to exit.
+fn launch(template: &str, files: &[&str]) -> Result<(), String> {
+    for line in command_lines(template, files)? {
+        match Command::new(&line[0]).args(&line[1..]).spawn() {
+            // Wait for the viewer in the background, so it doesn't linger as a zombie
+            Ok(mut child) => {
+                thread::spawn(move || child.wait());
+            }
+            Err(e) => return Err(format!("Couldn't launch {}: {}", line[0], e)),
+        }
+    }
+    Ok(())
+}
+
+/// Open `files` in their viewers. Files with the same viewer are opened together.
+///
+/// Files whose viewer can't be launched are opened with the fallback viewer instead.
+pub fn open(config: &Config, files: &[&str]) -> Result<(), String> {
+    let mut by_template = BTreeMap::new();
+    for &file in files {
+        by_template
+            .entry(template_for(config, file))
+            .or_insert_with(Vec::new)
+            .push(file);
+    }
+    for (template, files) in by_template {
+        let groups = if opens_each_file(template) {
+            files.iter().map(|&file| vec![file]).collect()
+        } else {
+            vec![files]
+        };
+        for files in groups {
+            if let Err(e) = launch(template, &files) {
+                if template == FALLBACK_VIEWER || launch(FALLBACK_VIEWER, &files).is_err() {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_command_lines() {
+    let strings = |words: &[&str]| words.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        command_lines("mpv --loop {files}", &["a.mp4", "b/c.mp4"]),
+        Ok(vec![strings(&["mpv", "--loop", "a.mp4", "b/c.mp4"])])
+    );
+    assert_eq!(
+        command_lines("zathura \"{file}\" --dir={dir}", &["a.pdf", "b/c.pdf"]),
+        Ok(vec![
+            strings(&["zathura", "a.pdf", "--dir=."]),
+            strings(&["zathura", "b/c.pdf", "--dir=b"]),
+        ])
+    );
+    assert_eq!(
+        command_lines("feh", &["a.png"]),
+        Ok(vec![strings(&["feh", "a.png"])])
+    );
+}